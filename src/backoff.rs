@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use crate::config::LinkOptions;
+
+/// How long a connection has to stay up to count as working.
+const STABLE_AFTER: Duration = Duration::from_secs(10);
+
+/// Exponential backoff between reconnection attempts.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(options: &LinkOptions) -> Self {
+        let initial = options.reconnect_delay();
+        Self {
+            initial,
+            max: options.reconnect_max_delay().max(initial),
+            current: initial,
+        }
+    }
+
+    /// Starts over from the initial delay if a connection stayed up long enough, so that a
+    /// peer that hangs up right away gets retried less and less often.
+    pub fn connection_lasted(&mut self, lasted: Duration) {
+        if lasted >= STABLE_AFTER {
+            self.current = self.initial;
+        }
+    }
+
+    /// Returns how long to wait before the next attempt, and doubles it for the one after.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        // somewhere between half and all of it, so that peers
+        // that went down together don't retry in lockstep
+        let half = delay / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> Backoff {
+        Backoff::new(&LinkOptions {
+            reconnect_delay: Some(1.0),
+            reconnect_max_delay: Some(8.0),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn short_connections_back_off() {
+        let mut backoff = backoff();
+        for max in [1, 2, 4, 8, 8] {
+            let delay = backoff.next_delay();
+            let max = Duration::from_secs(max);
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+            backoff.connection_lasted(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn lasting_connection_starts_over() {
+        let mut backoff = backoff();
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.connection_lasted(STABLE_AFTER);
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use tracing::level_filters::LevelFilter;
+
+pub const DEFAULT_CONFIG: &str = "ip2char.toml";
+
+/// Tunnel IP traffic through serial ports and other transports
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Configuration file [default: ip2char.toml]
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Maximum log level (off, error, warn, info, debug, trace)
+    #[arg(short, long, global = true, default_value = "info")]
+    pub log_level: LevelFilter,
+
+    /// Once detached from the terminal, append logs to this file instead of sending them to syslog
+    #[arg(long, global = true, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Without a subcommand, the tunnel is brought up in the foreground
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Bring the tunnel up, detaching from the terminal unless --foreground is given
+    Up {
+        /// Configuration file, same as --config
+        #[arg(value_name = "CONFIG")]
+        file: Option<PathBuf>,
+
+        /// Stay attached to the terminal
+        #[arg(short, long)]
+        foreground: bool,
+    },
+    /// Parse and validate the configuration, then exit
+    Check {
+        /// Configuration file, same as --config
+        #[arg(value_name = "CONFIG")]
+        file: Option<PathBuf>,
+    },
+}
+
+impl Cli {
+    /// Configuration file to use, the positional argument wins over --config.
+    pub fn config_path(&self) -> PathBuf {
+        let positional = match &self.command {
+            Some(Command::Up { file, .. }) | Some(Command::Check { file }) => file.as_ref(),
+            None => None,
+        };
+
+        positional
+            .or(self.config.as_ref())
+            .cloned()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG))
+    }
+}
@@ -1,8 +1,17 @@
-use ipnetwork::IpNetwork;
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::bail;
+use ipnetwork::{IpNetwork, Ipv6Network};
 use serde::{Deserialize, Serialize};
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
-use crate::types::{CompressionType, EncryptionType};
+use crate::types::{
+    CompressionType, ConnectionPolicy, EncryptionType, FlowControl, Framing, Key, Parity,
+};
+use crate::validation::{validate, Severity};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -19,6 +28,42 @@ pub struct Config {
     #[serde(rename = "peer-sock-listen")]
     #[serde(default)]
     pub peer_sock_listen: Vec<SockListenPeerSection>,
+
+    #[serde(rename = "peer-tls")]
+    #[serde(default)]
+    pub peer_tls: Vec<TlsPeerSection>,
+
+    #[serde(rename = "peer-tls-listen")]
+    #[serde(default)]
+    pub peer_tls_listen: Vec<TlsListenPeerSection>,
+
+    #[serde(rename = "peer-ws")]
+    #[serde(default)]
+    pub peer_ws: Vec<WsPeerSection>,
+
+    #[serde(rename = "peer-ws-listen")]
+    #[serde(default)]
+    pub peer_ws_listen: Vec<WsListenPeerSection>,
+
+    #[serde(rename = "peer-udp")]
+    #[serde(default)]
+    pub peer_udp: Vec<UdpPeerSection>,
+
+    #[serde(rename = "peer-unix")]
+    #[serde(default)]
+    pub peer_unix: Vec<UnixPeerSection>,
+
+    #[serde(rename = "peer-unix-listen")]
+    #[serde(default)]
+    pub peer_unix_listen: Vec<UnixListenPeerSection>,
+
+    #[serde(rename = "peer-stdio")]
+    #[serde(default)]
+    pub peer_stdio: Vec<StdioPeerSection>,
+
+    #[serde(rename = "peer-exec")]
+    #[serde(default)]
+    pub peer_exec: Vec<ExecPeerSection>,
 }
 
 impl Config {
@@ -36,6 +81,42 @@ impl Config {
             vec.push(Peer::SockListen(s.clone()));
         }
 
+        for t in &self.peer_tls {
+            vec.push(Peer::Tls(t.clone()));
+        }
+
+        for t in &self.peer_tls_listen {
+            vec.push(Peer::TlsListen(t.clone()));
+        }
+
+        for w in &self.peer_ws {
+            vec.push(Peer::Ws(w.clone()));
+        }
+
+        for w in &self.peer_ws_listen {
+            vec.push(Peer::WsListen(w.clone()));
+        }
+
+        for u in &self.peer_udp {
+            vec.push(Peer::Udp(u.clone()));
+        }
+
+        for u in &self.peer_unix {
+            vec.push(Peer::Unix(u.clone()));
+        }
+
+        for u in &self.peer_unix_listen {
+            vec.push(Peer::UnixListen(u.clone()));
+        }
+
+        for s in &self.peer_stdio {
+            vec.push(Peer::Stdio(s.clone()));
+        }
+
+        for e in &self.peer_exec {
+            vec.push(Peer::Exec(e.clone()));
+        }
+
         vec
     }
 }
@@ -43,45 +124,366 @@ impl Config {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InterfaceSection {
     pub address: IpNetwork,
+    #[serde(default)]
+    pub address6: Vec<Ipv6Network>,
     pub name: String,
     #[serde(rename = "ip-filtering")]
     pub ip_filtering: Option<bool>,
+    #[serde(rename = "filter-ipv6")]
+    pub filter_ipv6: Option<bool>,
     pub buffer: Option<usize>,
     #[serde(rename = "post-up")]
     pub post_up: Option<String>,
     #[serde(rename = "post-down")]
     pub post_down: Option<String>,
+    /// Static Curve25519 key used for Noise handshakes.
+    pub privatekey: Option<Key>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CharPeerSection {
-    pub path: String,
-    pub allowedips: Vec<IpNetwork>,
+    /// Device to open, can be left out to find it with the USB selectors below.
+    pub path: Option<String>,
+    /// USB vendor ID, in hex like `lsusb` prints it.
+    #[serde(rename = "usb-vid")]
+    pub usb_vid: Option<String>,
+    /// USB product ID, in hex like `lsusb` prints it.
+    #[serde(rename = "usb-pid")]
+    pub usb_pid: Option<String>,
+    /// USB serial number.
+    #[serde(rename = "usb-serial")]
+    pub usb_serial: Option<String>,
+    /// Pattern matched against the names in `/dev/serial/by-id`, `*` and `?` are wildcards.
+    #[serde(rename = "by-id")]
+    pub by_id: Option<String>,
     pub speed: Option<u32>,
+    /// 5 to 8, defaults to 8.
+    #[serde(rename = "data-bits")]
+    pub data_bits: Option<u8>,
     #[serde(default)]
-    pub compression: Option<CompressionType>,
+    pub parity: Option<Parity>,
+    /// 1 or 2, defaults to 1.
+    #[serde(rename = "stop-bits")]
+    pub stop_bits: Option<u8>,
+    #[serde(rename = "flow-control")]
     #[serde(default)]
-    pub encryption: Option<EncryptionType>,
+    pub flow_control: Option<FlowControl>,
+    /// Seconds without anything read before the link counts as down and the port is opened again.
+    pub timeout: Option<f64>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+    /// Number of damaged bytes that can be repaired in every 255-byte block.
+    pub fec: Option<u8>,
+}
+
+impl CharPeerSection {
+    /// Whether the device is found by its USB identity instead of its path.
+    pub fn has_usb_selector(&self) -> bool {
+        self.usb_vid.is_some()
+            || self.usb_pid.is_some()
+            || self.usb_serial.is_some()
+            || self.by_id.is_some()
+    }
+
+    /// The path, or a description of the device if it's found by its USB identity.
+    pub fn label(&self) -> Cow<'_, str> {
+        if let Some(path) = &self.path {
+            return Cow::Borrowed(path);
+        }
+        if let Some(pattern) = &self.by_id {
+            return Cow::Owned(format!("by-id:{}", pattern));
+        }
+
+        let mut label = format!(
+            "usb:{}:{}",
+            self.usb_vid.as_deref().unwrap_or("*"),
+            self.usb_pid.as_deref().unwrap_or("*")
+        );
+        if let Some(serial) = &self.usb_serial {
+            label.push(':');
+            label.push_str(serial);
+        }
+        Cow::Owned(label)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SockPeerSection {
     pub path: String,
-    pub allowedips: Vec<IpNetwork>,
-    #[serde(default)]
-    pub compression: Option<CompressionType>,
-    #[serde(default)]
-    pub encryption: Option<EncryptionType>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SockListenPeerSection {
     pub path: String,
+    /// What to do with a new connection while one is up.
+    #[serde(rename = "when-connected")]
+    pub when_connected: Option<ConnectionPolicy>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+impl SockListenPeerSection {
+    pub fn when_connected(&self) -> ConnectionPolicy {
+        self.when_connected.unwrap_or_default()
+    }
+
+    pub fn label(&self) -> Cow<'_, str> {
+        listener_label(&self.path, &self.options)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsPeerSection {
+    pub path: String,
+    /// Name the server's certificate has to be valid for, the host of `path` by default.
+    #[serde(rename = "server-name")]
+    pub server_name: Option<String>,
+    /// Certificate authorities to trust, in PEM.
+    pub ca: String,
+    /// Client certificate chain and its private key, in PEM, for servers that ask for one.
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+impl TlsPeerSection {
+    pub fn server_name(&self) -> &str {
+        if let Some(name) = &self.server_name {
+            return name;
+        }
+        let host = self
+            .path
+            .rsplit_once(':')
+            .map_or(&self.path[..], |(h, _)| h);
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsListenPeerSection {
+    pub path: String,
+    /// Certificate chain and its private key, in PEM.
+    pub cert: String,
+    pub key: String,
+    /// Only accept clients with a certificate signed by these authorities.
+    #[serde(rename = "client-ca")]
+    pub client_ca: Option<String>,
+    /// What to do with a new connection while one is up.
+    #[serde(rename = "when-connected")]
+    pub when_connected: Option<ConnectionPolicy>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+impl TlsListenPeerSection {
+    pub fn when_connected(&self) -> ConnectionPolicy {
+        self.when_connected.unwrap_or_default()
+    }
+
+    pub fn label(&self) -> Cow<'_, str> {
+        listener_label(&self.path, &self.options)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsPeerSection {
+    /// `ws://` or `wss://` URL of a `peer-ws-listen`, usually behind a reverse proxy.
+    pub url: String,
+    /// Certificate authorities to trust with `wss://`, in PEM, the system's by default.
+    pub ca: Option<String>,
+    /// Seconds between pings, so that proxies don't close idle connections.
+    pub keepalive: Option<f64>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsListenPeerSection {
+    pub path: String,
+    /// Only accept WebSocket connections to this path, any by default.
+    #[serde(rename = "url-path")]
+    pub url_path: Option<String>,
+    /// What to do with a new connection while one is up.
+    #[serde(rename = "when-connected")]
+    pub when_connected: Option<ConnectionPolicy>,
+    /// Seconds between pings, so that proxies don't close idle connections.
+    pub keepalive: Option<f64>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+impl WsListenPeerSection {
+    pub fn when_connected(&self) -> ConnectionPolicy {
+        self.when_connected.unwrap_or_default()
+    }
+
+    pub fn label(&self) -> Cow<'_, str> {
+        listener_label(&self.path, &self.options)
+    }
+}
+
+/// Clients sharing a listener only differ by their public key.
+fn listener_label<'a>(path: &'a str, options: &PeerOptions) -> Cow<'a, str> {
+    match (options.encryption(), &options.publickey) {
+        (EncryptionType::Noise, Some(key)) => {
+            Cow::Owned(format!("{} {}", path, &key.to_string()[..8]))
+        }
+        _ => Cow::Borrowed(path),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UdpPeerSection {
+    /// Local address to receive from, any port by default.
+    pub bind: Option<SocketAddr>,
+    /// Where to send frames to, `host:port`. Learnt from the incoming frames if left out.
+    pub remote: Option<String>,
+    /// Follow the peer when its frames start coming from somewhere else.
+    pub roaming: Option<bool>,
+    /// Seconds between keepalives sent to `remote`, so it knows where the peer is. 0 disables them.
+    pub keepalive: Option<f64>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+impl UdpPeerSection {
+    pub fn label(&self) -> Cow<'_, str> {
+        match (&self.remote, &self.bind) {
+            (Some(remote), _) => Cow::Owned(format!("udp:{}", remote)),
+            (None, Some(bind)) => Cow::Owned(format!("udp:{}", bind)),
+            (None, None) => Cow::Borrowed("udp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnixPeerSection {
+    pub path: String,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnixListenPeerSection {
+    pub path: String,
+    /// Permissions of the socket file, in octal like `chmod`.
+    pub mode: Option<String>,
+    /// Group owning the socket file, by name or ID.
+    pub group: Option<String>,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+/// Runs over the stdin and stdout of the process, to be started by ssh or socat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StdioPeerSection {
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+/// Runs over the stdin and stdout of a command, restarted whenever it exits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecPeerSection {
+    /// Run with `/bin/sh -c`.
+    pub command: String,
+    #[serde(flatten)]
+    pub options: PeerOptions,
+    #[serde(flatten)]
+    pub link: LinkOptions,
+}
+
+/// Which packets go to a peer and how they're carried, shared by every kind of peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerOptions {
     pub allowedips: Vec<IpNetwork>,
     #[serde(default)]
     pub compression: Option<CompressionType>,
     #[serde(default)]
     pub encryption: Option<EncryptionType>,
+    pub psk: Option<Key>,
+    pub publickey: Option<Key>,
+    /// Drop packets whose source isn't in `allowedips`.
+    #[serde(rename = "source-check")]
+    pub source_check: Option<bool>,
+}
+
+impl PeerOptions {
+    pub fn compression(&self) -> CompressionType {
+        self.compression.unwrap_or(CompressionType::None)
+    }
+
+    pub fn encryption(&self) -> EncryptionType {
+        self.encryption.unwrap_or(EncryptionType::None)
+    }
+
+    pub fn source_check(&self) -> bool {
+        self.source_check.unwrap_or(true)
+    }
+}
+
+/// How a peer gets reconnected, shared by every kind of peer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkOptions {
+    /// Seconds to wait before reconnecting, doubled after every failed attempt.
+    #[serde(rename = "reconnect-delay")]
+    pub reconnect_delay: Option<f64>,
+    /// Upper bound of `reconnect-delay`, in seconds.
+    #[serde(rename = "reconnect-max-delay")]
+    pub reconnect_max_delay: Option<f64>,
+    /// Keep the packets routed to the peer while it's down, instead of dropping them.
+    #[serde(rename = "queue-while-down")]
+    pub queue_while_down: Option<bool>,
+    /// How frames are delimited on byte streams.
+    pub framing: Option<Framing>,
+    /// Bits of the FCS asked of the peer with HDLC framing, 16 or 32.
+    pub fcs: Option<u8>,
+}
+
+impl LinkOptions {
+    pub fn reconnect_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.reconnect_delay.unwrap_or(1.0))
+    }
+
+    pub fn reconnect_max_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.reconnect_max_delay.unwrap_or(60.0))
+    }
+
+    pub fn queue_while_down(&self) -> bool {
+        self.queue_while_down.unwrap_or(false)
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing.unwrap_or_default()
+    }
+
+    pub fn fcs(&self) -> u8 {
+        self.fcs.unwrap_or(16)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -89,36 +491,122 @@ pub enum Peer {
     Char(CharPeerSection),
     Sock(SockPeerSection),
     SockListen(SockListenPeerSection),
+    Tls(TlsPeerSection),
+    TlsListen(TlsListenPeerSection),
+    Ws(WsPeerSection),
+    WsListen(WsListenPeerSection),
+    Udp(UdpPeerSection),
+    Unix(UnixPeerSection),
+    UnixListen(UnixListenPeerSection),
+    Stdio(StdioPeerSection),
+    Exec(ExecPeerSection),
 }
 
 impl Peer {
-    pub fn allowed_ips(&self) -> &[IpNetwork] {
+    /// Name of the config section the peer comes from.
+    pub fn section(&self) -> &'static str {
         match self {
-            Peer::Char(c) => &c.allowedips[..],
-            Peer::Sock(c) => &c.allowedips[..],
-            Peer::SockListen(c) => &c.allowedips[..],
+            Peer::Char(_) => "peer-char",
+            Peer::Sock(_) => "peer-sock",
+            Peer::SockListen(_) => "peer-sock-listen",
+            Peer::Tls(_) => "peer-tls",
+            Peer::TlsListen(_) => "peer-tls-listen",
+            Peer::Ws(_) => "peer-ws",
+            Peer::WsListen(_) => "peer-ws-listen",
+            Peer::Udp(_) => "peer-udp",
+            Peer::Unix(_) => "peer-unix",
+            Peer::UnixListen(_) => "peer-unix-listen",
+            Peer::Stdio(_) => "peer-stdio",
+            Peer::Exec(_) => "peer-exec",
         }
     }
 
-    pub fn path(&self) -> &str {
+    pub fn options(&self) -> &PeerOptions {
         match self {
-            Peer::Char(c) => &c.path,
-            Peer::Sock(c) => &c.path,
-            Peer::SockListen(c) => &c.path,
+            Peer::Char(c) => &c.options,
+            Peer::Sock(c) => &c.options,
+            Peer::SockListen(c) => &c.options,
+            Peer::Tls(c) => &c.options,
+            Peer::TlsListen(c) => &c.options,
+            Peer::Ws(c) => &c.options,
+            Peer::WsListen(c) => &c.options,
+            Peer::Udp(c) => &c.options,
+            Peer::Unix(c) => &c.options,
+            Peer::UnixListen(c) => &c.options,
+            Peer::Stdio(c) => &c.options,
+            Peer::Exec(c) => &c.options,
+        }
+    }
+
+    pub fn allowed_ips(&self) -> &[IpNetwork] {
+        &self.options().allowedips
+    }
+
+    /// Where the peer is, used to name it in the logs.
+    pub fn path(&self) -> Cow<'_, str> {
+        match self {
+            Peer::Char(c) => c.label(),
+            Peer::Sock(c) => Cow::Borrowed(&c.path),
+            Peer::SockListen(c) => c.label(),
+            Peer::Tls(c) => Cow::Borrowed(&c.path),
+            Peer::TlsListen(c) => c.label(),
+            Peer::Ws(c) => Cow::Borrowed(&c.url),
+            Peer::WsListen(c) => c.label(),
+            Peer::Udp(c) => c.label(),
+            Peer::Unix(c) => Cow::Borrowed(&c.path),
+            Peer::UnixListen(c) => Cow::Borrowed(&c.path),
+            Peer::Stdio(_) => Cow::Borrowed("stdio"),
+            Peer::Exec(c) => Cow::Borrowed(&c.command),
         }
     }
 
     pub fn compression(&self) -> CompressionType {
+        self.options().compression()
+    }
+
+    pub fn encryption(&self) -> EncryptionType {
+        self.options().encryption()
+    }
+
+    pub fn psk(&self) -> Option<&Key> {
+        self.options().psk.as_ref()
+    }
+
+    pub fn public_key(&self) -> Option<&Key> {
+        self.options().publickey.as_ref()
+    }
+
+    pub fn source_check(&self) -> bool {
+        self.options().source_check()
+    }
+
+    pub fn link(&self) -> &LinkOptions {
+        match self {
+            Peer::Char(c) => &c.link,
+            Peer::Sock(c) => &c.link,
+            Peer::SockListen(c) => &c.link,
+            Peer::Tls(c) => &c.link,
+            Peer::TlsListen(c) => &c.link,
+            Peer::Ws(c) => &c.link,
+            Peer::WsListen(c) => &c.link,
+            Peer::Udp(c) => &c.link,
+            Peer::Unix(c) => &c.link,
+            Peer::UnixListen(c) => &c.link,
+            Peer::Stdio(c) => &c.link,
+            Peer::Exec(c) => &c.link,
+        }
+    }
+
+    pub fn fec(&self) -> Option<u8> {
         match self {
-            Peer::Char(c) => c.compression.unwrap_or(CompressionType::None),
-            Peer::Sock(c) => c.compression.unwrap_or(CompressionType::None),
-            Peer::SockListen(c) => c.compression.unwrap_or(CompressionType::None),
+            Peer::Char(c) => c.fec.filter(|&n| n > 0),
+            _ => None,
         }
     }
 }
 
-pub async fn parse_config() -> anyhow::Result<(Config, Vec<Peer>)> {
-    let config_text = tokio::fs::read_to_string("ip2char.toml").await?;
+pub fn parse_config(path: &Path) -> anyhow::Result<(Config, Vec<Peer>)> {
+    let config_text = std::fs::read_to_string(path)?;
     let config = toml::from_str::<Config>(&config_text)?;
     info!("[0] Read config file.");
 
@@ -128,5 +616,21 @@ pub async fn parse_config() -> anyhow::Result<(Config, Vec<Peer>)> {
         warn!("Zero peers listed in configuration file!");
     }
 
+    let issues = validate(&config, &all_peers);
+    for issue in &issues {
+        match issue.severity {
+            Severity::Error => error!("{}", issue),
+            Severity::Warning => warn!("{}", issue),
+        }
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|i| i.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        bail!("{} error(s) in configuration", errors);
+    }
+
     Ok((config, all_peers))
 }
@@ -0,0 +1,456 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+use thiserror::Error;
+
+use crate::config::Peer;
+use crate::noise::Noise;
+use crate::replay::ReplayWindow;
+use crate::types::{EncryptionType, FrameKind, Header, Key};
+use crate::utils::lock;
+use crate::HEADER_SIZE;
+
+pub const SALT_SIZE: usize = 4;
+pub const TAG_SIZE: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum CryptoError {
+    #[error("frame too short")]
+    TooShort,
+
+    #[error("authentication failed")]
+    BadTag,
+
+    #[error("our own frame {0} sent back")]
+    Reflected(u64),
+
+    #[error("encryption failed")]
+    Seal,
+
+    #[error("unexpected encryption {0:?}")]
+    UnexpectedEncryption(EncryptionType),
+
+    #[error("no session for key id {0}")]
+    NoSession(u8),
+
+    #[error("unexpected handshake")]
+    UnexpectedHandshake,
+
+    #[error("handshake from unknown peer")]
+    UnknownPeer,
+
+    #[error("replayed handshake")]
+    Replay,
+
+    #[error("handshakes crossed")]
+    Crossed,
+
+    #[error("replayed frame {0}")]
+    Replayed(u64),
+
+    #[error("frame {0} is too old")]
+    TooOld(u64),
+
+    #[error("handshake failed: {0}")]
+    Handshake(#[from] snow::Error),
+}
+
+fn nonce(salt: &[u8], counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..SALT_SIZE].copy_from_slice(salt);
+    nonce[SALT_SIZE..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// The whole header is authenticated, except for the crc,
+/// which is computed over the encrypted frame.
+fn associated_data(header: &Header) -> [u8; HEADER_SIZE] {
+    let mut header = *header;
+    header.crc = 0;
+    header.into()
+}
+
+/// Encrypts outgoing frames.
+///
+/// Encrypted payloads are laid out as `salt || ciphertext || tag`.
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    counter: u64,
+}
+
+impl Sealer {
+    pub fn new(key: &Key) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.0.into()),
+            // both directions of a link share the same key,
+            // the random salt keeps their nonces apart
+            salt: rand::random(),
+            // counting from the current time, so that nonces
+            // never repeat after a restart
+            counter: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+        }
+    }
+
+    pub fn salt(&self) -> [u8; SALT_SIZE] {
+        self.salt
+    }
+
+    /// Encrypts `payload` into `out`, and fills in the counter and length of `header`.
+    pub fn seal(
+        &mut self,
+        header: &mut Header,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), CryptoError> {
+        self.counter += 1;
+        header.counter = self.counter;
+        header.packet_length = (SALT_SIZE + payload.len() + TAG_SIZE) as u16;
+
+        out.clear();
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(payload);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                &nonce(&self.salt, self.counter),
+                &associated_data(header),
+                &mut out[SALT_SIZE..],
+            )
+            .map_err(|_| CryptoError::Seal)?;
+        out.extend_from_slice(&tag);
+        Ok(())
+    }
+}
+
+/// Decrypts incoming frames, and rejects replayed ones.
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    replay: ReplayWindow,
+}
+
+impl Opener {
+    pub fn new(key: &Key) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.0.into()),
+            replay: ReplayWindow::new(),
+        }
+    }
+
+    /// Authenticates and decrypts `payload` into `out`.
+    pub fn open(
+        &mut self,
+        header: &Header,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), CryptoError> {
+        self.check(header, payload, out)?;
+        self.replay.update(header.counter);
+        Ok(())
+    }
+
+    /// Like `open`, but leaves the frame to be opened again.
+    fn check(&self, header: &Header, payload: &[u8], out: &mut Vec<u8>) -> Result<(), CryptoError> {
+        if payload.len() < SALT_SIZE + TAG_SIZE {
+            return Err(CryptoError::TooShort);
+        }
+        self.replay.check(header.counter)?;
+        let (salt, rest) = payload.split_at(SALT_SIZE);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+        out.clear();
+        out.extend_from_slice(ciphertext);
+        self.cipher
+            .decrypt_in_place_detached(
+                &nonce(salt, header.counter),
+                &associated_data(header),
+                out,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| CryptoError::BadTag)
+    }
+}
+
+/// Encryption state of a peer, shared by both halves of its connection.
+pub enum Crypto {
+    None,
+    Psk {
+        sealer: Mutex<Sealer>,
+        opener: Mutex<Opener>,
+        /// the sealer's, to recognize our own frames
+        salt: [u8; SALT_SIZE],
+    },
+    Noise(Box<Mutex<Noise>>),
+}
+
+impl Crypto {
+    pub fn new(peer: &Peer, private_key: Option<&Key>) -> anyhow::Result<Self> {
+        match peer.encryption() {
+            EncryptionType::None => Ok(Crypto::None),
+            EncryptionType::ChaCha20Poly1305 => {
+                let psk = peer
+                    .psk()
+                    .ok_or_else(|| anyhow!("chacha20poly1305 encryption requires a psk"))?;
+                Ok(Crypto::psk(psk))
+            }
+            EncryptionType::Noise => {
+                let private_key = private_key
+                    .ok_or_else(|| anyhow!("noise encryption requires an interface privatekey"))?;
+                let public_key = peer
+                    .public_key()
+                    .ok_or_else(|| anyhow!("noise encryption requires a peer publickey"))?;
+                // a listener can have several clients, which it only tells apart by their handshake
+                let initiator = !matches!(
+                    peer,
+                    Peer::SockListen(_) | Peer::TlsListen(_) | Peer::WsListen(_)
+                );
+                let noise = Noise::new(*private_key, *public_key, peer.psk().copied(), initiator)?;
+                Ok(Crypto::Noise(Box::new(Mutex::new(noise))))
+            }
+        }
+    }
+
+    fn psk(psk: &Key) -> Self {
+        let sealer = Sealer::new(psk);
+        Crypto::Psk {
+            salt: sealer.salt(),
+            sealer: Mutex::new(sealer),
+            opener: Mutex::new(Opener::new(psk)),
+        }
+    }
+
+    pub fn encryption(&self) -> EncryptionType {
+        match self {
+            Crypto::None => EncryptionType::None,
+            Crypto::Psk { .. } => EncryptionType::ChaCha20Poly1305,
+            Crypto::Noise(_) => EncryptionType::Noise,
+        }
+    }
+
+    /// Called when a new connection is made.
+    pub fn reset(&self) {
+        if let Crypto::Noise(noise) = self {
+            lock(noise).reset();
+        }
+    }
+
+    /// Whether frames can be encrypted yet.
+    pub fn is_ready(&self) -> bool {
+        match self {
+            Crypto::Noise(noise) => lock(noise).is_ready(),
+            _ => true,
+        }
+    }
+
+    /// Encrypts `payload` into `out`, and fills in the encryption fields of `header`.
+    pub fn seal(
+        &self,
+        header: &mut Header,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), CryptoError> {
+        header.encryption = self.encryption();
+        match self {
+            Crypto::None => {
+                out.clear();
+                out.extend_from_slice(payload);
+                Ok(())
+            }
+            Crypto::Psk { sealer, .. } => lock(sealer).seal(header, payload, out),
+            Crypto::Noise(noise) => {
+                let mut noise = lock(noise);
+                let (key_id, sealer) = noise.sealer().ok_or(CryptoError::NoSession(0))?;
+                header.key_id = key_id;
+                sealer.seal(header, payload, out)
+            }
+        }
+    }
+
+    /// Authenticates and decrypts `payload` into `out`.
+    pub fn open(
+        &self,
+        header: &Header,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), CryptoError> {
+        if header.encryption != self.encryption() {
+            return Err(CryptoError::UnexpectedEncryption(header.encryption));
+        }
+        match self {
+            Crypto::None => {
+                out.clear();
+                out.extend_from_slice(payload);
+                Ok(())
+            }
+            // the same key goes both ways, so our own frames would authenticate if sent back
+            Crypto::Psk { salt, .. } if payload.starts_with(salt) => {
+                Err(CryptoError::Reflected(header.counter))
+            }
+            Crypto::Psk { opener, .. } => lock(opener).open(header, payload, out),
+            Crypto::Noise(noise) => lock(noise)
+                .opener(header.key_id)
+                .ok_or(CryptoError::NoSession(header.key_id))?
+                .open(header, payload, out),
+        }
+    }
+
+    /// Checks that the first frame of a connection comes from the peer, without taking it in,
+    /// for listeners to know who connected before handing the connection over.
+    pub fn verify(&self, header: &Header, payload: &[u8]) -> Result<(), CryptoError> {
+        if header.encryption != self.encryption() {
+            return Err(CryptoError::UnexpectedEncryption(header.encryption));
+        }
+        match self {
+            Crypto::None => Ok(()),
+            Crypto::Psk { salt, .. } if payload.starts_with(salt) => {
+                Err(CryptoError::Reflected(header.counter))
+            }
+            Crypto::Psk { opener, .. } => lock(opener).check(header, payload, &mut Vec::new()),
+            Crypto::Noise(_) if header.kind != FrameKind::Handshake => {
+                Err(CryptoError::NoSession(header.key_id))
+            }
+            Crypto::Noise(noise) => lock(noise).check_initiation(payload),
+        }
+    }
+
+    /// Starts a new handshake if one is due, with its first message in `out`.
+    pub fn initiate(&self, out: &mut Vec<u8>) -> Result<Option<Header>, CryptoError> {
+        let Crypto::Noise(noise) = self else {
+            return Ok(None);
+        };
+        Ok(lock(noise).initiate(out)?.map(handshake_header))
+    }
+
+    /// Handles a handshake frame, returning the header of the reply to send back, if any.
+    pub fn handshake(
+        &self,
+        header: &Header,
+        message: &[u8],
+        reply: &mut Vec<u8>,
+    ) -> Result<Option<Header>, CryptoError> {
+        let Crypto::Noise(noise) = self else {
+            return Err(CryptoError::UnexpectedHandshake);
+        };
+        let respond = lock(noise).handle(header.key_id, message, reply)?;
+        Ok(respond.then(|| handshake_header(header.key_id)))
+    }
+
+    /// Switches to the session of the last handshake reply, once it's been sent.
+    pub fn promote(&self) -> Option<u8> {
+        match self {
+            Crypto::Noise(noise) => lock(noise).promote(),
+            _ => None,
+        }
+    }
+}
+
+fn handshake_header(key_id: u8) -> Header {
+    Header {
+        encryption: EncryptionType::Noise,
+        kind: FrameKind::Handshake,
+        key_id,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(crypto: &Crypto, payload: &[u8]) -> (Header, Vec<u8>) {
+        let mut header = Header::default();
+        let mut sealed = Vec::new();
+        crypto.seal(&mut header, payload, &mut sealed).unwrap();
+        (header, sealed)
+    }
+
+    #[test]
+    fn psk_round_trip() {
+        let key = Key([7; 32]);
+        let (a, b) = (Crypto::psk(&key), Crypto::psk(&key));
+        let mut out = Vec::new();
+        for payload in [&b"hello"[..], b"", &[0; 1500]] {
+            let (header, sealed) = frame(&a, payload);
+            let mut tampered = header;
+            tampered.compression = crate::types::CompressionType::Zstd;
+            assert!(matches!(
+                b.open(&tampered, &sealed, &mut out),
+                Err(CryptoError::BadTag)
+            ));
+
+            b.open(&header, &sealed, &mut out).unwrap();
+            assert_eq!(out, payload);
+            assert!(matches!(
+                b.open(&header, &sealed, &mut out),
+                Err(CryptoError::Replayed(_))
+            ));
+        }
+
+        let (header, mut sealed) = frame(&b, b"hello");
+        sealed[SALT_SIZE] ^= 1;
+        assert!(matches!(
+            a.open(&header, &sealed, &mut out),
+            Err(CryptoError::BadTag)
+        ));
+    }
+
+    #[test]
+    fn psk_reflection() {
+        let key = Key([7; 32]);
+        let (a, b) = (Crypto::psk(&key), Crypto::psk(&key));
+        let mut out = Vec::new();
+        // a started long after b, so its counter is way ahead
+        if let Crypto::Psk { sealer, .. } = &a {
+            lock(sealer).counter += 1 << 40;
+        }
+
+        let (header, sealed) = frame(&b, b"early");
+        let (reflected, mut reflected_sealed) = frame(&a, b"mine");
+        assert!(matches!(
+            a.open(&reflected, &reflected_sealed, &mut out),
+            Err(CryptoError::Reflected(_))
+        ));
+        // and the replay window didn't move to a's counter
+        a.open(&header, &sealed, &mut out).unwrap();
+        assert_eq!(out, b"early");
+
+        // nor does it get through with another salt, which is part of the nonce
+        reflected_sealed[0] ^= 1;
+        assert!(matches!(
+            a.open(&reflected, &reflected_sealed, &mut out),
+            Err(CryptoError::BadTag)
+        ));
+    }
+
+    #[test]
+    fn psk_verify() {
+        let key = Key([7; 32]);
+        let (a, b) = (Crypto::psk(&key), Crypto::psk(&key));
+        let other = Crypto::psk(&Key([8; 32]));
+        let mut out = Vec::new();
+
+        let (header, sealed) = frame(&a, b"");
+        assert!(matches!(
+            other.verify(&header, &sealed),
+            Err(CryptoError::BadTag)
+        ));
+        // verifying leaves the frame to be opened, once
+        b.verify(&header, &sealed).unwrap();
+        b.verify(&header, &sealed).unwrap();
+        b.open(&header, &sealed, &mut out).unwrap();
+        assert!(matches!(
+            b.verify(&header, &sealed),
+            Err(CryptoError::Replayed(_))
+        ));
+        assert!(matches!(
+            a.verify(&header, &sealed),
+            Err(CryptoError::Reflected(_))
+        ));
+    }
+}
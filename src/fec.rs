@@ -0,0 +1,143 @@
+use anyhow::bail;
+use reed_solomon::{Decoder, Encoder};
+
+use crate::HEADER_SIZE;
+
+/// Reed-Solomon over GF(2^8) can't have codewords longer than this.
+const BLOCK_SIZE: usize = 255;
+
+/// The header must fit in a single block, so that it can be repaired on its own.
+const HEADER_MAX_ERRORS: usize = (BLOCK_SIZE - HEADER_SIZE) / 2;
+
+/// The decoder multiplies `2 * errors + 1` syndromes by an error locator of up to
+/// `errors + 1` terms, and its polynomials can't be longer than 256.
+const DECODER_MAX_ERRORS: usize = BLOCK_SIZE / 3;
+
+pub const MAX_ERRORS: u8 = if HEADER_MAX_ERRORS < DECODER_MAX_ERRORS {
+    HEADER_MAX_ERRORS as u8
+} else {
+    DECODER_MAX_ERRORS as u8
+};
+
+/// Reed-Solomon forward error correction.
+///
+/// Data is split in blocks of up to `255 - 2 * errors` bytes, and each block
+/// is followed by `2 * errors` parity bytes, so that up to `errors` damaged
+/// bytes can be repaired in every block.
+pub struct Fec {
+    encoder: Encoder,
+    decoder: Decoder,
+    ecc_len: usize,
+}
+
+impl Fec {
+    pub fn new(errors: u8) -> anyhow::Result<Self> {
+        if errors > MAX_ERRORS {
+            bail!("FEC can repair at most {} bytes per block", MAX_ERRORS);
+        }
+        let ecc_len = errors as usize * 2;
+        Ok(Self {
+            encoder: Encoder::new(ecc_len),
+            decoder: Decoder::new(ecc_len),
+            ecc_len,
+        })
+    }
+
+    pub fn ecc_len(&self) -> usize {
+        self.ecc_len
+    }
+
+    fn data_len(&self) -> usize {
+        BLOCK_SIZE - self.ecc_len
+    }
+
+    /// Size of `len` bytes of data once encoded.
+    pub fn encoded_len(&self, len: usize) -> usize {
+        len + len.div_ceil(self.data_len()) * self.ecc_len
+    }
+
+    pub fn encode(&self, data: &[u8], out: &mut Vec<u8>) {
+        for block in data.chunks(self.data_len()) {
+            out.extend_from_slice(&self.encoder.encode(block));
+        }
+    }
+
+    /// Decodes and repairs `data` into `out`.
+    ///
+    /// Returns the number of repaired bytes, or `None` if a block
+    /// had too many errors to be repaired.
+    pub fn decode(&self, data: &[u8], out: &mut Vec<u8>) -> Option<usize> {
+        let mut corrected = 0;
+        for block in data.chunks(BLOCK_SIZE) {
+            if block.len() <= self.ecc_len {
+                return None;
+            }
+            let (buf, n) = self.decoder.correct_err_count(block, None).ok()?;
+            out.extend_from_slice(buf.data());
+            corrected += n;
+        }
+        Some(corrected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes two blocks worth of data, flips `flips` bytes in the first one and decodes it.
+    fn round_trip(errors: u8, flips: usize) -> Option<(usize, Vec<u8>)> {
+        let fec = Fec::new(errors).unwrap();
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut encoded = vec![];
+        fec.encode(&data, &mut encoded);
+        assert_eq!(encoded.len(), fec.encoded_len(data.len()));
+
+        for i in 0..flips {
+            encoded[i * 7 % BLOCK_SIZE] ^= 0x5a;
+        }
+        let mut decoded = vec![];
+        let corrected = fec.decode(&encoded, &mut decoded)?;
+        assert_eq!(decoded, data);
+        Some((corrected, decoded))
+    }
+
+    #[test]
+    fn repairs_up_to_max_errors() {
+        for errors in [1, 8, MAX_ERRORS] {
+            for flips in 0..=errors as usize {
+                let (corrected, _) = round_trip(errors, flips).unwrap();
+                assert_eq!(corrected, flips);
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_past_max_errors() {
+        for errors in [1, 8, MAX_ERRORS] {
+            assert!(round_trip(errors, errors as usize + 1).is_none());
+        }
+    }
+
+    #[test]
+    fn survives_garbage() {
+        let fec = Fec::new(MAX_ERRORS).unwrap();
+        let mut x: u32 = 1;
+        for _ in 0..200 {
+            let garbage: Vec<u8> = (0..BLOCK_SIZE)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    x as u8
+                })
+                .collect();
+            fec.decode(&garbage, &mut vec![]);
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_errors() {
+        assert!(Fec::new(MAX_ERRORS).is_ok());
+        assert!(Fec::new(MAX_ERRORS + 1).is_err());
+    }
+}
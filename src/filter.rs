@@ -0,0 +1,165 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+
+use ipnetwork::IpNetwork;
+use tracing::trace;
+
+use crate::config::InterfaceSection;
+use crate::stats::FilterStats;
+
+#[derive(Debug, Copy, Clone)]
+pub enum DropReason {
+    Broadcast,
+    Multicast,
+    LinkLocal,
+    Ipv6,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DropReason::Broadcast => "broadcast",
+            DropReason::Multicast => "multicast",
+            DropReason::LinkLocal => "link-local",
+            DropReason::Ipv6 => "IPv6",
+        })
+    }
+}
+
+/// Drops packets that would only waste the bandwidth of the transport,
+/// because the kernel at the other side of the tunnel would filter them anyway.
+pub struct Filter {
+    enabled: bool,
+    filter_ipv6: bool,
+    subnet_broadcast: Option<Ipv4Addr>,
+    pub stats: FilterStats,
+}
+
+impl Filter {
+    pub fn new(interface: &InterfaceSection) -> Self {
+        let subnet_broadcast = match interface.address {
+            IpNetwork::V4(network) if network.prefix() < 31 => Some(network.broadcast()),
+            _ => None,
+        };
+        Self {
+            enabled: interface.ip_filtering.unwrap_or(true),
+            filter_ipv6: interface.filter_ipv6.unwrap_or(false),
+            subnet_broadcast,
+            stats: FilterStats::default(),
+        }
+    }
+
+    fn check(&self, destination: IpAddr) -> Option<DropReason> {
+        match destination {
+            IpAddr::V4(ip) if ip.is_broadcast() || Some(ip) == self.subnet_broadcast => {
+                Some(DropReason::Broadcast)
+            }
+            IpAddr::V4(ip) if ip.is_multicast() => Some(DropReason::Multicast),
+            IpAddr::V4(ip) if ip.is_link_local() => Some(DropReason::LinkLocal),
+            IpAddr::V4(_) => None,
+            IpAddr::V6(_) if self.filter_ipv6 => Some(DropReason::Ipv6),
+            IpAddr::V6(ip) if ip.is_multicast() => Some(DropReason::Multicast),
+            // fe80::/10
+            IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => Some(DropReason::LinkLocal),
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    /// Whether a packet to `destination` should be sent to the peers.
+    pub fn allow(&self, destination: IpAddr) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let Some(reason) = self.check(destination) else {
+            return true;
+        };
+        let n = self.stats.bump(reason);
+        trace!(
+            "Filtered {} packet to {} ({} so far)",
+            reason,
+            destination,
+            n
+        );
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    fn filter(options: &str) -> Filter {
+        let interface = format!("address = '10.1.0.1/24'\nname = 'test'\n{}", options);
+        Filter::new(&toml::from_str(&interface).unwrap())
+    }
+
+    fn allows(filter: &Filter, destination: &str) -> bool {
+        filter.allow(destination.parse().unwrap())
+    }
+
+    #[test]
+    fn drops_what_the_kernel_would() {
+        let filter = filter("");
+        for (destination, counter) in [
+            ("255.255.255.255", &filter.stats.broadcast),
+            ("10.1.0.255", &filter.stats.broadcast),
+            ("224.0.0.251", &filter.stats.multicast),
+            ("239.255.255.250", &filter.stats.multicast),
+            ("169.254.12.34", &filter.stats.link_local),
+            ("ff02::1", &filter.stats.multicast),
+            ("ff05::1:3", &filter.stats.multicast),
+            ("fe80::1", &filter.stats.link_local),
+            ("febf::1", &filter.stats.link_local),
+        ] {
+            let before = counter.load(Ordering::Relaxed);
+            assert!(!allows(&filter, destination), "{}", destination);
+            assert_eq!(
+                counter.load(Ordering::Relaxed),
+                before + 1,
+                "{}",
+                destination
+            );
+        }
+    }
+
+    #[test]
+    fn lets_unicast_through() {
+        let filter = filter("");
+        for destination in [
+            "10.1.0.2",
+            "10.1.1.255",
+            "192.168.1.1",
+            "fd00::2",
+            "fec0::1",
+            "2001:db8::1",
+        ] {
+            assert!(allows(&filter, destination), "{}", destination);
+        }
+    }
+
+    #[test]
+    fn drops_ipv6_when_asked() {
+        let filter = filter("filter-ipv6 = true");
+        assert!(!allows(&filter, "fd00::2"));
+        assert_eq!(filter.stats.ipv6.load(Ordering::Relaxed), 1);
+        assert!(allows(&filter, "10.1.0.2"));
+    }
+
+    #[test]
+    fn point_to_point_has_no_subnet_broadcast() {
+        let interface = "address = '10.1.0.0/31'\nname = 'test'";
+        let filter = Filter::new(&toml::from_str(interface).unwrap());
+        assert!(allows(&filter, "10.1.0.1"));
+        assert!(!allows(&filter, "255.255.255.255"));
+    }
+
+    #[test]
+    fn can_be_turned_off() {
+        let filter = filter("ip-filtering = false\nfilter-ipv6 = true");
+        for destination in ["255.255.255.255", "224.0.0.251", "fe80::1", "fd00::2"] {
+            assert!(allows(&filter, destination), "{}", destination);
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use std::sync::Arc;
+
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf,
+    WriteHalf,
+};
+use tracing::warn;
+
+use crate::framing::{
+    decode_frame, encode_frame, header_bytes, BadFrame, FrameReader, FrameWriter, MAX_PAYLOAD,
+};
+use crate::stats::PeerStats;
+use crate::streams::PeerState;
+use crate::types::{Header, MARKER_SIZE, SYNC_MARKER};
+use crate::HEADER_SIZE;
+
+/// Starts the payload of the keepalive frames that offer COBS to the peer,
+/// which peers that don't know about it ignore like any keepalive.
+const OFFER: &[u8] = b"cobs";
+/// Longest encoded frame, with a code byte every 254 bytes.
+const MAX_ENCODED: usize = (HEADER_SIZE + MAX_PAYLOAD) * 255 / 254 + 1;
+
+/// Payload of a COBS offer, telling whether the peer's own offer was heard.
+pub fn offer(heard: bool) -> Vec<u8> {
+    [OFFER, &[heard as u8]].concat()
+}
+
+/// Whether a keepalive payload is a COBS offer, and if so whether the peer heard ours.
+pub fn parse_offer(payload: &[u8]) -> Option<bool> {
+    match payload.strip_prefix(OFFER)? {
+        [heard] => Some(*heard != 0),
+        _ => None,
+    }
+}
+
+/// Frames encoded with COBS and ended by a zero byte, which can't appear anywhere else,
+/// so that a damaged frame never costs more than itself.
+///
+/// Frames from a peer that's still sending them back to back are read as well, until it
+/// switches to COBS.
+pub struct CobsReader<R> {
+    stream: BufReader<ReadHalf<R>>,
+    path: String,
+    state: Arc<PeerState>,
+    chunk: Vec<u8>,
+    frame: Vec<u8>,
+    /// whether the last frame was damaged, so that a burst of noise only counts once
+    damaged: bool,
+}
+
+impl<R: AsyncRead> CobsReader<R> {
+    pub fn new(stream: ReadHalf<R>, path: String, state: Arc<PeerState>) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            path,
+            state,
+            chunk: Vec::with_capacity(MAX_ENCODED),
+            frame: Vec::with_capacity(HEADER_SIZE + MAX_PAYLOAD),
+            damaged: false,
+        }
+    }
+
+    /// Reads the bytes up to the next zero into `chunk`, returns false if there were too many.
+    async fn read_chunk(&mut self) -> anyhow::Result<bool> {
+        self.chunk.clear();
+        let mut fits = true;
+        loop {
+            let buf = self.stream.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            let (used, ended) = match buf.iter().position(|&b| b == 0) {
+                Some(end) => (end + 1, true),
+                None => (buf.len(), false),
+            };
+            let bytes = &buf[..used - ended as usize];
+            if self.chunk.len() + bytes.len() <= MAX_ENCODED {
+                self.chunk.extend_from_slice(bytes);
+            } else {
+                fits = false;
+            }
+            self.stream.consume(used);
+            if ended {
+                return Ok(fits);
+            }
+        }
+    }
+
+    /// Reads the rest of a frame sent the old way, whose header got cut by the zero in its version.
+    async fn read_raw(
+        &mut self,
+        payload: &mut Vec<u8>,
+    ) -> anyhow::Result<Result<Header, BadFrame>> {
+        let start = self.chunk.len() - MARKER_SIZE - 1;
+        let mut header_buf = [0u8; HEADER_SIZE];
+        header_buf[..MARKER_SIZE + 1].copy_from_slice(&self.chunk[start..]);
+        self.stream
+            .read_exact(&mut header_buf[MARKER_SIZE + 2..])
+            .await?;
+        let header = match Header::from_slice(&header_buf) {
+            Ok(h) if (h.packet_length as usize) <= MAX_PAYLOAD => h,
+            Ok(_) => return Ok(Err(BadFrame::Corrupt)),
+            Err(e) => return Ok(Err(BadFrame::from_header_error(&e))),
+        };
+        payload.resize(header.packet_length as usize, 0);
+        self.stream.read_exact(payload).await?;
+
+        Ok(match header.checksum(payload) == header.crc {
+            true => Ok(header),
+            false => Err(BadFrame::Corrupt),
+        })
+    }
+}
+
+impl<R> FrameReader for CobsReader<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    async fn read_frame(&mut self, payload: &mut Vec<u8>) -> anyhow::Result<Header> {
+        loop {
+            let fits = self.read_chunk().await?;
+            if fits && self.chunk.is_empty() {
+                continue;
+            }
+
+            let header = if !fits {
+                Err(BadFrame::Corrupt)
+            } else if is_raw_start(&self.chunk) {
+                self.read_raw(payload).await?
+            } else {
+                self.frame.clear();
+                match decode(&self.chunk, &mut self.frame) {
+                    true => decode_frame(&self.frame).map(|(header, data)| {
+                        payload.clear();
+                        payload.extend_from_slice(data);
+                        header
+                    }),
+                    false => Err(BadFrame::Corrupt),
+                }
+            };
+            match header {
+                Ok(header) => {
+                    self.damaged = false;
+                    return Ok(header);
+                }
+                Err(BadFrame::Corrupt) if !self.damaged => {
+                    self.damaged = true;
+                    let n = PeerStats::bump(&self.state.stats.corrupt_frames);
+                    warn!("[{}] Dropped corrupt frame ({} so far)", self.path, n);
+                }
+                Err(BadFrame::Corrupt) => {}
+                Err(bad) => self.state.drop_frame(&self.path, bad),
+            }
+        }
+    }
+}
+
+/// Whether a chunk ends with the start of a frame sent the old way, cut right after the
+/// low byte of its version.
+fn is_raw_start(chunk: &[u8]) -> bool {
+    chunk.len() > MARKER_SIZE
+        && chunk[chunk.len() - MARKER_SIZE - 1..][..MARKER_SIZE] == SYNC_MARKER
+}
+
+/// Sends frames back to back like `RawWriter`, until the peer says it reads COBS.
+pub struct CobsWriter<W> {
+    stream: WriteHalf<W>,
+    cobs: bool,
+    frame: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<W> CobsWriter<W> {
+    pub fn new(stream: WriteHalf<W>) -> Self {
+        Self {
+            stream,
+            cobs: false,
+            frame: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<W> FrameWriter for CobsWriter<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn write_frame(&mut self, header: Header, payload: &[u8]) -> anyhow::Result<()> {
+        if !self.cobs {
+            self.stream
+                .write_all(&header_bytes(header, payload))
+                .await?;
+            self.stream.write_all(payload).await?;
+            return Ok(());
+        }
+        self.frame.clear();
+        encode_frame(header, payload, &mut self.frame);
+        self.buf.clear();
+        encode(&self.frame, &mut self.buf);
+        self.buf.push(0);
+        self.stream.write_all(&self.buf).await?;
+        Ok(())
+    }
+
+    fn use_cobs(&mut self) {
+        self.cobs = true;
+    }
+}
+
+/// Appends `data` to `out` without any zero byte, each run of non-zero bytes being
+/// preceded by its length plus one.
+fn encode(data: &[u8], out: &mut Vec<u8>) {
+    let mut code_at = out.len();
+    out.push(0);
+    let mut code = 1u8;
+    for &byte in data {
+        if byte != 0 {
+            out.push(byte);
+            code += 1;
+        }
+        if byte == 0 || code == 0xff {
+            out[code_at] = code;
+            code_at = out.len();
+            out.push(0);
+            code = 1;
+        }
+    }
+    out[code_at] = code;
+}
+
+/// Reverses `encode`, returns false if `data` can't have come from it.
+fn decode(data: &[u8], out: &mut Vec<u8>) -> bool {
+    let mut i = 0;
+    while i < data.len() {
+        let code = data[i] as usize;
+        let end = i + code;
+        if code == 0 || end > data.len() {
+            return false;
+        }
+        out.extend_from_slice(&data[i + 1..end]);
+        i = end;
+        // a full run isn't followed by a zero
+        if code < 0xff && i < data.len() {
+            out.push(0);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        encode(data, &mut encoded);
+        assert!(!encoded.contains(&0), "{:?}", encoded);
+        let mut decoded = Vec::new();
+        assert!(decode(&encoded, &mut decoded));
+        assert_eq!(decoded, data);
+        encoded
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(round_trip(&[]), [1]);
+    }
+
+    #[test]
+    fn no_zeros() {
+        assert_eq!(round_trip(&[1, 2, 3]), [4, 1, 2, 3]);
+    }
+
+    #[test]
+    fn zeros() {
+        assert_eq!(round_trip(&[0]), [1, 1]);
+        assert_eq!(round_trip(&[0, 0]), [1, 1, 1]);
+        assert_eq!(round_trip(&[1, 0, 2]), [2, 1, 2, 2]);
+        assert_eq!(round_trip(&[1, 2, 0]), [3, 1, 2, 1]);
+    }
+
+    #[test]
+    fn long_runs() {
+        for len in [253, 254, 255, 508, 509, 1600] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 255 + 1) as u8).collect();
+            let encoded = round_trip(&data);
+            assert_eq!(encoded[0], (len + 1).min(0xff) as u8);
+            round_trip(&[&data[..], &[0]].concat());
+            round_trip(&[&[0], &data[..], &[0, 7]].concat());
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let mut out = Vec::new();
+        // a zero can't be in there
+        assert!(!decode(&[2, 1, 0, 1], &mut out));
+        assert!(!decode(&[0], &mut out));
+        // runs past the end
+        assert!(!decode(&[5, 1, 2, 3], &mut out));
+        assert!(!decode(&[2, 1, 0xff], &mut out));
+    }
+}
@@ -0,0 +1,378 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+use tokio::select;
+use tokio::sync::Mutex;
+use tokio::time::Interval;
+use tracing::{info, warn};
+
+use crate::framing::ppp::{Params, Ppp, IPV4, LCP};
+use crate::framing::{FrameReader, FrameWriter, MAX_PAYLOAD};
+use crate::stats::PeerStats;
+use crate::streams::PeerState;
+use crate::types::{FrameKind, Header};
+
+const FLAG: u8 = 0x7e;
+const ESCAPE: u8 = 0x7d;
+/// What escaped bytes get XORed with.
+const FLIP: u8 = 0x20;
+const ADDRESS: u8 = 0xff;
+const CONTROL: u8 = 0x03;
+
+/// Address, control and protocol fields, plus the longest FCS.
+const OVERHEAD: usize = 8;
+/// How long to wait for an answer before asking again, as in RFC 1661.
+const RESTART: Duration = Duration::from_secs(3);
+
+const FCS16_TABLE: [u16; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut fcs = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            fcs = if fcs & 1 != 0 {
+                (fcs >> 1) ^ 0x8408
+            } else {
+                fcs >> 1
+            };
+            bit += 1;
+        }
+        table[i] = fcs;
+        i += 1;
+    }
+    table
+};
+
+/// Starts a PPP link over a byte stream, framed as in RFC 1662, and hands out its halves.
+///
+/// The reader runs the negotiation, and writes its answers through the writer's stream.
+pub fn new<R: AsyncRead, W>(
+    read: ReadHalf<R>,
+    write: WriteHalf<W>,
+    local: Option<Ipv4Addr>,
+    remote: Option<Ipv4Addr>,
+    fcs32: bool,
+    path: String,
+    state: Arc<PeerState>,
+) -> (HdlcReader<R, W>, HdlcWriter<W>) {
+    let link = Arc::new(Mutex::new(Link {
+        stream: write,
+        params: Params::default(),
+        up: false,
+        buf: Vec::new(),
+    }));
+    let reader = HdlcReader {
+        deframer: Deframer {
+            stream: BufReader::new(read),
+            frame: Vec::with_capacity(MAX_PAYLOAD + OVERHEAD),
+            done: true,
+            escaped: false,
+            intact: true,
+        },
+        ppp: Ppp::new(path.clone(), local, remote, fcs32),
+        link: link.clone(),
+        timer: tokio::time::interval(RESTART),
+        path,
+        state,
+    };
+
+    (reader, HdlcWriter { link })
+}
+
+/// The writing half of the stream, with what the peer asked for.
+struct Link<W> {
+    stream: WriteHalf<W>,
+    params: Params,
+    /// whether IP packets can go through
+    up: bool,
+    buf: Vec<u8>,
+}
+
+impl<W: AsyncWrite> Link<W> {
+    async fn send(&mut self, protocol: u16, data: &[u8]) -> io::Result<()> {
+        // LCP has to get through even if the peer's idea of the line is wrong
+        let accm = match protocol {
+            LCP => u32::MAX,
+            _ => self.params.accm,
+        };
+        let header = [ADDRESS, CONTROL, (protocol >> 8) as u8, protocol as u8];
+        let mut fcs16 = !0;
+        let mut fcs32 = crc32fast::Hasher::new();
+        for part in [&header[..], data] {
+            if self.params.fcs32 {
+                fcs32.update(part);
+            } else {
+                fcs16 = fcs16_update(fcs16, part);
+            }
+        }
+        let fcs16 = (!fcs16).to_le_bytes();
+        let fcs32 = fcs32.finalize().to_le_bytes();
+        let fcs = if self.params.fcs32 {
+            &fcs32[..]
+        } else {
+            &fcs16[..]
+        };
+
+        self.buf.clear();
+        self.buf.push(FLAG);
+        for &byte in header.iter().chain(data).chain(fcs) {
+            let control = byte < 0x20 && accm & (1 << byte) != 0;
+            if byte == FLAG || byte == ESCAPE || control {
+                self.buf.extend_from_slice(&[ESCAPE, byte ^ FLIP]);
+            } else {
+                self.buf.push(byte);
+            }
+        }
+        self.buf.push(FLAG);
+        self.stream.write_all(&self.buf).await
+    }
+}
+
+/// Splits a byte stream into frames between flags, keeping its progress in between calls so
+/// that waiting for one can be cancelled.
+struct Deframer<R> {
+    stream: BufReader<ReadHalf<R>>,
+    frame: Vec<u8>,
+    /// whether `frame` holds a whole frame, to start over next time
+    done: bool,
+    escaped: bool,
+    intact: bool,
+}
+
+impl<R: AsyncRead> Deframer<R> {
+    /// Reads up to the next flag, returns whether what came before it is intact.
+    async fn next(&mut self, accm: u32) -> anyhow::Result<bool> {
+        if self.done {
+            self.frame.clear();
+            self.done = false;
+            self.escaped = false;
+            self.intact = true;
+        }
+        loop {
+            let buf = self.stream.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let mut used = 0;
+            for &byte in buf {
+                used += 1;
+                let byte = match (self.escaped, byte) {
+                    (_, FLAG) => {
+                        self.done = true;
+                        break;
+                    }
+                    (false, ESCAPE) => {
+                        self.escaped = true;
+                        continue;
+                    }
+                    // put there by something on the line, the peer would have escaped it
+                    (false, byte) if byte < 0x20 && accm & (1 << byte) != 0 => continue,
+                    (false, byte) => byte,
+                    (true, byte) => byte ^ FLIP,
+                };
+                self.escaped = false;
+                if self.frame.len() < MAX_PAYLOAD + OVERHEAD {
+                    self.frame.push(byte);
+                } else {
+                    self.intact = false;
+                }
+            }
+            self.stream.consume(used);
+            if self.done {
+                return Ok(self.intact && !self.escaped);
+            }
+        }
+    }
+}
+
+/// IPv4 packets over PPP in HDLC-like frames, as in RFC 1662, to talk to pppd or routers.
+///
+/// Like SLIP, there's no room for headers, so the packets can't be compressed or encrypted.
+pub struct HdlcReader<R, W> {
+    deframer: Deframer<R>,
+    ppp: Ppp,
+    link: Arc<Mutex<Link<W>>>,
+    timer: Interval,
+    path: String,
+    state: Arc<PeerState>,
+}
+
+impl<R, W: AsyncWrite> HdlcReader<R, W> {
+    /// Sends what the negotiation came up with, and applies its outcome.
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        let mut link = self.link.lock().await;
+        let (before, after) = (link.params, self.ppp.tx());
+        for (protocol, packet) in self.ppp.replies.drain(..) {
+            // the ack that brings LCP up still goes the old way, the peer isn't up until it gets it
+            link.params = match protocol {
+                LCP if after != Params::default() => before,
+                _ => after,
+            };
+            link.send(protocol, &packet).await?;
+        }
+        link.params = after;
+        let up = self.ppp.is_up();
+        if up != link.up {
+            link.up = up;
+            info!("[{}] PPP is {}.", self.path, if up { "up" } else { "down" });
+        }
+        Ok(())
+    }
+}
+
+impl<R, W> FrameReader for HdlcReader<R, W>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn read_frame(&mut self, payload: &mut Vec<u8>) -> anyhow::Result<Header> {
+        loop {
+            let rx = self.ppp.rx();
+            let intact = select! {
+                intact = self.deframer.next(rx.accm) => intact?,
+                _ = self.timer.tick() => {
+                    self.ppp.tick();
+                    self.flush().await?;
+                    continue;
+                }
+            };
+            let frame = &self.deframer.frame;
+            // frames can share their flags or not
+            if intact && frame.is_empty() {
+                continue;
+            }
+            // the peer may have started over with the default FCS
+            let body = if intact {
+                check_fcs(frame, rx.fcs32).or_else(|| check_fcs(frame, !rx.fcs32))
+            } else {
+                None
+            };
+            let Some((protocol, data)) = body.and_then(split_protocol) else {
+                let n = PeerStats::bump(&self.state.stats.corrupt_frames);
+                warn!("[{}] Dropped corrupt frame ({} so far)", self.path, n);
+                continue;
+            };
+
+            if protocol == IPV4 {
+                payload.clear();
+                payload.extend_from_slice(data);
+                return Ok(Header {
+                    packet_length: payload.len() as u16,
+                    ..Default::default()
+                });
+            }
+            let handled = self.ppp.handle(protocol, data);
+            self.flush().await?;
+            handled?;
+        }
+    }
+}
+
+pub struct HdlcWriter<W> {
+    link: Arc<Mutex<Link<W>>>,
+}
+
+impl<W> FrameWriter for HdlcWriter<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn write_frame(&mut self, header: Header, payload: &[u8]) -> anyhow::Result<()> {
+        let mut link = self.link.lock().await;
+        // there's nowhere to put anything but packets, and only IPv4 ones until IPCP is up
+        if header.kind != FrameKind::Data || !link.up || payload.first().map(|b| b >> 4) != Some(4)
+        {
+            return Ok(());
+        }
+        link.send(IPV4, payload).await?;
+        Ok(())
+    }
+}
+
+fn fcs16_update(mut fcs: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        fcs = (fcs >> 8) ^ FCS16_TABLE[((fcs ^ byte as u16) & 0xff) as usize];
+    }
+    fcs
+}
+
+/// Strips the FCS off a frame, if it matches.
+fn check_fcs(frame: &[u8], fcs32: bool) -> Option<&[u8]> {
+    if fcs32 {
+        let (body, fcs) = frame.split_last_chunk::<4>()?;
+        (crc32fast::hash(body) == u32::from_le_bytes(*fcs)).then_some(body)
+    } else {
+        let (body, fcs) = frame.split_last_chunk::<2>()?;
+        (!fcs16_update(!0, body) == u16::from_le_bytes(*fcs)).then_some(body)
+    }
+}
+
+/// Splits a frame into its protocol and data, whether the peer compresses its fields or not.
+fn split_protocol(frame: &[u8]) -> Option<(u16, &[u8])> {
+    let frame = frame.strip_prefix(&[ADDRESS, CONTROL]).unwrap_or(frame);
+    match frame {
+        // protocols end with an odd byte, so a short one only has that
+        [low, data @ ..] if low & 1 != 0 => Some((*low as u16, data)),
+        [high, low, data @ ..] if low & 1 != 0 => Some((u16::from_be_bytes([*high, *low]), data)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the FCS comes to over a frame followed by its own, good FCS (RFC 1662, C.2 and C.3).
+    const GOOD_FCS16: u16 = 0xf0b8;
+    const GOOD_FCS32: u32 = 0xdebb20e3;
+
+    #[test]
+    fn fcs16() {
+        // the check value of CRC-16/X-25
+        assert_eq!(!fcs16_update(!0, b"123456789"), 0x906e);
+
+        let data = [ADDRESS, CONTROL, 0xc0, 0x21, 1, 1, 0, 4];
+        let fcs = (!fcs16_update(!0, &data)).to_le_bytes();
+        let frame = [&data[..], &fcs].concat();
+        assert_eq!(fcs16_update(!0, &frame), GOOD_FCS16);
+        assert_eq!(check_fcs(&frame, false), Some(&data[..]));
+    }
+
+    #[test]
+    fn fcs32() {
+        assert_eq!(crc32fast::hash(b"123456789"), 0xcbf43926);
+
+        let data = [ADDRESS, CONTROL, 0xc0, 0x21, 1, 1, 0, 4];
+        let fcs = crc32fast::hash(&data).to_le_bytes();
+        let frame = [&data[..], &fcs].concat();
+        assert_eq!(!crc32fast::hash(&frame), GOOD_FCS32);
+        assert_eq!(check_fcs(&frame, true), Some(&data[..]));
+    }
+
+    #[test]
+    fn bad_fcs() {
+        let data = [ADDRESS, CONTROL, 0x00, 0x21, 0x45];
+        let fcs = (!fcs16_update(!0, &data)).to_le_bytes();
+        let mut frame = [&data[..], &fcs].concat();
+        assert_eq!(check_fcs(&frame, true), None);
+        frame[4] ^= 0x10;
+        assert_eq!(check_fcs(&frame, false), None);
+        assert_eq!(check_fcs(&[0x12], false), None);
+    }
+
+    #[test]
+    fn protocol_field() {
+        assert_eq!(
+            split_protocol(&[ADDRESS, CONTROL, 0xc0, 0x21, 1]),
+            Some((LCP, &[1][..]))
+        );
+        // compressed address and control, and protocol
+        assert_eq!(split_protocol(&[0x21, 0x45]), Some((IPV4, &[0x45][..])));
+        assert_eq!(split_protocol(&[0xc0, 0x20]), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::future::Future;
+
+use crate::types::{Header, IntoErrors};
+use crate::HEADER_SIZE;
+
+pub mod cobs;
+pub mod hdlc;
+pub mod ppp;
+pub mod raw;
+pub mod slip;
+pub mod vj;
+
+/// Biggest frame payload that's accepted, an MTU-sized packet with some room for overhead.
+pub const MAX_PAYLOAD: usize = 1600;
+
+/// Where the frames coming from a peer get read from.
+pub trait FrameReader {
+    /// Reads the next intact frame, putting its payload in `payload` and skipping damaged ones.
+    ///
+    /// Errors mean that the connection is gone.
+    fn read_frame(
+        &mut self,
+        payload: &mut Vec<u8>,
+    ) -> impl Future<Output = anyhow::Result<Header>> + Send;
+
+    /// Called when the last frame read turned out to be genuine.
+    fn accept(&mut self) {}
+}
+
+/// Where the frames going to a peer get written to.
+pub trait FrameWriter {
+    fn write_frame(
+        &mut self,
+        header: Header,
+        payload: &[u8],
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Called once the peer said it reads COBS frames, for writers that can send them.
+    fn use_cobs(&mut self) {}
+}
+
+/// Fills in the length and checksum of a header, and serializes it.
+pub fn header_bytes(mut header: Header, payload: &[u8]) -> [u8; HEADER_SIZE] {
+    header.packet_length = payload.len() as u16;
+    header.crc = header.checksum(payload);
+    header.into()
+}
+
+/// Appends a whole frame to `out`, for transports that keep frames apart by themselves.
+pub fn encode_frame(header: Header, payload: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&header_bytes(header, payload));
+    out.extend_from_slice(payload);
+}
+
+/// Why a frame got dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadFrame {
+    Corrupt,
+    /// The peer speaks another version of the protocol.
+    OtherVersion(u16),
+}
+
+impl BadFrame {
+    /// What a header that couldn't be parsed says about its frame.
+    pub fn from_header_error(e: &anyhow::Error) -> Self {
+        match e.downcast_ref() {
+            Some(IntoErrors::OtherVersion(version)) => BadFrame::OtherVersion(*version),
+            _ => BadFrame::Corrupt,
+        }
+    }
+}
+
+/// Splits a whole frame into its header and payload, if it's intact.
+pub fn decode_frame(frame: &[u8]) -> Result<(Header, &[u8]), BadFrame> {
+    let header = Header::from_slice(frame).map_err(|e| BadFrame::from_header_error(&e))?;
+    let payload = &frame[HEADER_SIZE..];
+    if payload.len() == header.packet_length as usize && header.checksum(payload) == header.crc {
+        Ok((header, payload))
+    } else {
+        Err(BadFrame::Corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VERSION;
+
+    fn frame(header: Header, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![];
+        encode_frame(header, payload, &mut frame);
+        frame
+    }
+
+    #[test]
+    fn round_trip() {
+        let frame = frame(Header::default(), b"payload");
+        let (header, payload) = decode_frame(&frame).unwrap();
+        assert_eq!(payload, b"payload");
+        assert_eq!(header.packet_length, 7);
+    }
+
+    #[test]
+    fn flipped_bit_is_corrupt() {
+        let mut frame = frame(Header::default(), b"payload");
+        frame[HEADER_SIZE + 2] ^= 0x10;
+        assert_eq!(decode_frame(&frame).err(), Some(BadFrame::Corrupt));
+    }
+
+    #[test]
+    fn wrong_length_is_corrupt() {
+        let mut frame = frame(Header::default(), b"payload");
+        frame[6..8].copy_from_slice(&5u16.to_le_bytes());
+        assert_eq!(decode_frame(&frame).err(), Some(BadFrame::Corrupt));
+        // a datagram cut short
+        let frame = self::frame(Header::default(), b"payload");
+        assert_eq!(
+            decode_frame(&frame[..frame.len() - 1]).err(),
+            Some(BadFrame::Corrupt)
+        );
+    }
+
+    #[test]
+    fn other_version_is_told_apart() {
+        let other = Header {
+            version: VERSION + 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_frame(&frame(other, b"payload")).err(),
+            Some(BadFrame::OtherVersion(VERSION + 1))
+        );
+    }
+}
@@ -0,0 +1,636 @@
+//! Just enough of PPP (RFC 1661) to carry IPv4 packets: LCP to bring the link up, and IPCP
+//! (RFC 1332) to tell each other's addresses.
+//!
+//! Whatever can be refused is: there's no authentication, and nothing gets compressed.
+
+use std::net::Ipv4Addr;
+
+use anyhow::bail;
+use tracing::{info, warn};
+
+pub const LCP: u16 = 0xc021;
+pub const IPCP: u16 = 0x8021;
+pub const IPV4: u16 = 0x0021;
+
+// codes of the control packets, shared by LCP and IPCP
+const CONFIGURE_REQUEST: u8 = 1;
+const CONFIGURE_ACK: u8 = 2;
+const CONFIGURE_NAK: u8 = 3;
+const CONFIGURE_REJECT: u8 = 4;
+const TERMINATE_REQUEST: u8 = 5;
+const TERMINATE_ACK: u8 = 6;
+const CODE_REJECT: u8 = 7;
+// LCP only
+const PROTOCOL_REJECT: u8 = 8;
+const ECHO_REQUEST: u8 = 9;
+const ECHO_REPLY: u8 = 10;
+const DISCARD_REQUEST: u8 = 11;
+const IDENTIFICATION: u8 = 12;
+const TIME_REMAINING: u8 = 13;
+
+// LCP options
+const MRU: u8 = 1;
+const ACCM: u8 = 2;
+const MAGIC_NUMBER: u8 = 5;
+const FCS_ALTERNATIVES: u8 = 9;
+// IPCP options
+const IP_ADDRESS: u8 = 3;
+
+// FCS-Alternatives (RFC 1570)
+const FCS_16: u8 = 0x02;
+const FCS_32: u8 = 0x04;
+
+/// Longest control packet sent, the MRU everyone has to accept.
+const MAX_PACKET: usize = 1500;
+
+/// How frames are sent or expected, which the peers only change once LCP is up.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Params {
+    /// control characters that have to be escaped, one bit each
+    pub accm: u32,
+    pub fcs32: bool,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            accm: u32::MAX,
+            fcs32: false,
+        }
+    }
+}
+
+/// Where one of the control protocols is at, both ways.
+#[derive(Default)]
+struct Negotiation {
+    /// options of our Configure-Request
+    options: Vec<(u8, Vec<u8>)>,
+    id: u8,
+    /// whether the peer acked our request
+    acked: bool,
+    /// the options of the peer's request we acked, if we did
+    acking: Option<Vec<(u8, Vec<u8>)>>,
+}
+
+impl Negotiation {
+    fn is_open(&self) -> bool {
+        self.acked && self.acking.is_some()
+    }
+
+    fn option(&self, kind: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, data)| &data[..])
+    }
+
+    fn set_option(&mut self, kind: u8, data: &[u8]) {
+        match self.options.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, old)) => *old = data.to_vec(),
+            None => self.options.push((kind, data.to_vec())),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.acked = false;
+        self.acking = None;
+    }
+}
+
+/// What to answer to an option of the peer's Configure-Request.
+enum Verdict {
+    Ack,
+    /// suggests another value
+    Nak(Vec<u8>),
+    Reject,
+}
+
+/// The LCP and IPCP negotiations with a peer, whose answers pile up in `replies`.
+pub struct Ppp {
+    path: String,
+    lcp: Negotiation,
+    ipcp: Negotiation,
+    magic: u32,
+    /// address the peer gets if it asks for one
+    remote: Option<Ipv4Addr>,
+    /// identifier of the last Code-Reject or Protocol-Reject
+    reject_id: u8,
+    /// control packets to send, with their protocol
+    pub replies: Vec<(u16, Vec<u8>)>,
+}
+
+impl Ppp {
+    pub fn new(
+        path: String,
+        local: Option<Ipv4Addr>,
+        remote: Option<Ipv4Addr>,
+        fcs32: bool,
+    ) -> Self {
+        let magic = new_magic();
+        let mut lcp = Negotiation::default();
+        // nothing needs escaping on a clean line
+        lcp.set_option(ACCM, &[0; 4]);
+        lcp.set_option(MAGIC_NUMBER, &magic.to_be_bytes());
+        if fcs32 {
+            lcp.set_option(FCS_ALTERNATIVES, &[FCS_32]);
+        }
+        let mut ipcp = Negotiation::default();
+        ipcp.set_option(IP_ADDRESS, &local.unwrap_or(Ipv4Addr::UNSPECIFIED).octets());
+
+        Self {
+            path,
+            lcp,
+            ipcp,
+            magic,
+            remote,
+            reject_id: 0,
+            replies: Vec::new(),
+        }
+    }
+
+    /// Whether IP packets can go through.
+    pub fn is_up(&self) -> bool {
+        self.lcp.is_open() && self.ipcp.is_open()
+    }
+
+    /// How to send frames, as the peer asked.
+    pub fn tx(&self) -> Params {
+        match &self.lcp.acking {
+            Some(options) if self.lcp.acked => {
+                let mut params = Params::default();
+                for (kind, data) in options {
+                    match (*kind, &data[..]) {
+                        (ACCM, &[a, b, c, d]) => params.accm = u32::from_be_bytes([a, b, c, d]),
+                        (FCS_ALTERNATIVES, &[fcs]) => params.fcs32 = fcs & FCS_32 != 0,
+                        _ => {}
+                    }
+                }
+                params
+            }
+            _ => Params::default(),
+        }
+    }
+
+    /// How frames are coming, as we asked.
+    pub fn rx(&self) -> Params {
+        if !self.lcp.is_open() {
+            return Params::default();
+        }
+        let mut params = Params::default();
+        if let Some(&[a, b, c, d]) = self.lcp.option(ACCM) {
+            params.accm = u32::from_be_bytes([a, b, c, d]);
+        }
+        params.fcs32 = self.lcp.option(FCS_ALTERNATIVES) == Some(&[FCS_32][..]);
+        params
+    }
+
+    /// Sends the Configure-Requests that haven't been acked yet, again.
+    pub fn tick(&mut self) {
+        if !self.lcp.acked {
+            request(LCP, &mut self.lcp, &mut self.replies);
+        } else if self.lcp.is_open() && !self.ipcp.acked {
+            request(IPCP, &mut self.ipcp, &mut self.replies);
+        }
+    }
+
+    /// Handles a packet of any protocol but IPv4, errors mean that the peer closed the link.
+    pub fn handle(&mut self, protocol: u16, packet: &[u8]) -> anyhow::Result<()> {
+        match (protocol, parse_packet(packet)) {
+            (LCP, Some((code, id, data))) => {
+                let was_open = self.lcp.is_open();
+                self.handle_lcp(code, id, data)?;
+                if self.lcp.is_open() != was_open {
+                    // everything above LCP starts over with it
+                    self.ipcp.reset();
+                    if self.lcp.is_open() {
+                        request(IPCP, &mut self.ipcp, &mut self.replies);
+                    }
+                }
+            }
+            (IPCP, Some((code, id, data))) if self.lcp.is_open() => {
+                self.handle_ipcp(code, id, data)
+            }
+            // nothing but LCP counts until LCP is up
+            (LCP | IPCP, _) => {}
+            _ if self.lcp.is_open() => {
+                let mut rejected = protocol.to_be_bytes().to_vec();
+                rejected.extend_from_slice(packet);
+                self.reject(LCP, PROTOCOL_REJECT, &rejected);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Sends back what the peer sent and we don't understand.
+    fn reject(&mut self, protocol: u16, code: u8, rejected: &[u8]) {
+        self.reject_id = self.reject_id.wrapping_add(1);
+        let rejected = &rejected[..rejected.len().min(MAX_PACKET - 4)];
+        self.replies
+            .push((protocol, make_packet(code, self.reject_id, rejected)));
+    }
+
+    fn handle_lcp(&mut self, code: u8, id: u8, data: &[u8]) -> anyhow::Result<()> {
+        match code {
+            CONFIGURE_REQUEST => {
+                let magic = self.magic;
+                configure(
+                    LCP,
+                    &mut self.lcp,
+                    id,
+                    data,
+                    &mut self.replies,
+                    |kind, data| {
+                        match (kind, data.len()) {
+                            (MRU, 2) | (ACCM, 4) => Verdict::Ack,
+                            // the same number twice means the line loops back to us
+                            (MAGIC_NUMBER, 4) if data == magic.to_be_bytes() => {
+                                Verdict::Nak(new_magic().to_be_bytes().to_vec())
+                            }
+                            (MAGIC_NUMBER, 4) => Verdict::Ack,
+                            (FCS_ALTERNATIVES, 1) if data[0] & (FCS_16 | FCS_32) == data[0] => {
+                                Verdict::Ack
+                            }
+                            (FCS_ALTERNATIVES, 1) => Verdict::Nak(vec![FCS_16]),
+                            _ => Verdict::Reject,
+                        }
+                    },
+                );
+            }
+            CONFIGURE_ACK => {
+                if id == self.lcp.id {
+                    self.lcp.acked = true;
+                }
+            }
+            CONFIGURE_NAK | CONFIGURE_REJECT if id == self.lcp.id => {
+                for (kind, value) in options(data).unwrap_or_default() {
+                    match (code, kind) {
+                        (CONFIGURE_NAK, ACCM) if value.len() == 4 => {
+                            self.lcp.set_option(ACCM, value)
+                        }
+                        (CONFIGURE_NAK, MAGIC_NUMBER) => {
+                            self.magic = new_magic();
+                            self.lcp.set_option(MAGIC_NUMBER, &self.magic.to_be_bytes());
+                        }
+                        // anything else is dropped, the FCS falls back to 16 bits
+                        (CONFIGURE_NAK, FCS_ALTERNATIVES) | (CONFIGURE_REJECT, _) => {
+                            if kind == FCS_ALTERNATIVES {
+                                info!("[{}] Peer refused FCS-32, using FCS-16.", self.path);
+                            }
+                            self.lcp.options.retain(|(k, _)| *k != kind);
+                        }
+                        _ => {}
+                    }
+                }
+                request(LCP, &mut self.lcp, &mut self.replies);
+            }
+            TERMINATE_REQUEST => {
+                self.replies
+                    .push((LCP, make_packet(TERMINATE_ACK, id, &[])));
+                bail!("Peer closed the PPP link");
+            }
+            ECHO_REQUEST if self.lcp.is_open() => {
+                let mut reply = self.magic.to_be_bytes().to_vec();
+                reply.extend_from_slice(data.get(4..).unwrap_or_default());
+                self.replies
+                    .push((LCP, make_packet(ECHO_REPLY, id, &reply)));
+            }
+            PROTOCOL_REJECT if data.starts_with(&IPCP.to_be_bytes()) => {
+                warn!("[{}] Peer doesn't carry IPv4.", self.path);
+            }
+            CONFIGURE_NAK | CONFIGURE_REJECT | TERMINATE_ACK | CODE_REJECT | PROTOCOL_REJECT
+            | ECHO_REQUEST | ECHO_REPLY | DISCARD_REQUEST | IDENTIFICATION | TIME_REMAINING => {}
+            _ => self.reject(LCP, CODE_REJECT, &make_packet(code, id, data)),
+        }
+        Ok(())
+    }
+
+    fn handle_ipcp(&mut self, code: u8, id: u8, data: &[u8]) {
+        match code {
+            CONFIGURE_REQUEST => {
+                let remote = self.remote;
+                configure(
+                    IPCP,
+                    &mut self.ipcp,
+                    id,
+                    data,
+                    &mut self.replies,
+                    |kind, data| match (kind, data.len(), remote) {
+                        (IP_ADDRESS, 4, Some(remote)) if data == [0; 4] => {
+                            Verdict::Nak(remote.octets().to_vec())
+                        }
+                        (IP_ADDRESS, 4, None) if data == [0; 4] => Verdict::Reject,
+                        (IP_ADDRESS, 4, _) => Verdict::Ack,
+                        _ => Verdict::Reject,
+                    },
+                );
+            }
+            CONFIGURE_ACK => {
+                if id == self.ipcp.id {
+                    self.ipcp.acked = true;
+                }
+            }
+            CONFIGURE_NAK | CONFIGURE_REJECT if id == self.ipcp.id => {
+                for (kind, value) in options(data).unwrap_or_default() {
+                    match (code, kind) {
+                        // the interface keeps its address, but the peer won't agree to anything else
+                        (CONFIGURE_NAK, IP_ADDRESS) => {
+                            let &[a, b, c, d] = value else { continue };
+                            if self.ipcp.option(IP_ADDRESS) != Some(&[0; 4][..]) {
+                                warn!(
+                                    "[{}] Peer wants us at {}, not at the interface's address.",
+                                    self.path,
+                                    Ipv4Addr::new(a, b, c, d)
+                                );
+                            }
+                            self.ipcp.set_option(IP_ADDRESS, value);
+                        }
+                        (CONFIGURE_REJECT, _) => self.ipcp.options.retain(|(k, _)| *k != kind),
+                        _ => {}
+                    }
+                }
+                request(IPCP, &mut self.ipcp, &mut self.replies);
+            }
+            TERMINATE_REQUEST => {
+                self.ipcp.reset();
+                self.replies
+                    .push((IPCP, make_packet(TERMINATE_ACK, id, &[])));
+            }
+            CONFIGURE_NAK | CONFIGURE_REJECT | TERMINATE_ACK | CODE_REJECT => {}
+            _ => self.reject(IPCP, CODE_REJECT, &make_packet(code, id, data)),
+        }
+    }
+}
+
+fn new_magic() -> u32 {
+    // zero means that there's no magic number
+    rand::random::<u32>().max(1)
+}
+
+/// Sends our Configure-Request, with a new identifier.
+fn request(protocol: u16, negotiation: &mut Negotiation, replies: &mut Vec<(u16, Vec<u8>)>) {
+    negotiation.id = negotiation.id.wrapping_add(1);
+    negotiation.acked = false;
+    let mut data = Vec::new();
+    for (kind, value) in &negotiation.options {
+        data.push(*kind);
+        data.push(value.len() as u8 + 2);
+        data.extend_from_slice(value);
+    }
+    replies.push((
+        protocol,
+        make_packet(CONFIGURE_REQUEST, negotiation.id, &data),
+    ));
+}
+
+/// Answers the peer's Configure-Request, rejecting or suggesting changes to the options
+/// that `judge` doesn't like, and acking it if there are none.
+fn configure(
+    protocol: u16,
+    negotiation: &mut Negotiation,
+    id: u8,
+    data: &[u8],
+    replies: &mut Vec<(u16, Vec<u8>)>,
+    mut judge: impl FnMut(u8, &[u8]) -> Verdict,
+) {
+    let Some(requested) = options(data) else {
+        return;
+    };
+    if negotiation.is_open() {
+        // the peer starts over, so we have to as well
+        request(protocol, negotiation, replies);
+    }
+
+    let (mut naks, mut rejects) = (Vec::new(), Vec::new());
+    for &(kind, value) in &requested {
+        match judge(kind, value) {
+            Verdict::Ack => {}
+            Verdict::Nak(suggested) => {
+                naks.push(kind);
+                naks.push(suggested.len() as u8 + 2);
+                naks.extend_from_slice(&suggested);
+            }
+            Verdict::Reject => {
+                rejects.push(kind);
+                rejects.push(value.len() as u8 + 2);
+                rejects.extend_from_slice(value);
+            }
+        }
+    }
+    let (code, answer) = if !rejects.is_empty() {
+        (CONFIGURE_REJECT, &rejects[..])
+    } else if !naks.is_empty() {
+        (CONFIGURE_NAK, &naks[..])
+    } else {
+        (CONFIGURE_ACK, data)
+    };
+    negotiation.acking = (code == CONFIGURE_ACK).then(|| {
+        requested
+            .iter()
+            .map(|&(kind, value)| (kind, value.to_vec()))
+            .collect()
+    });
+    replies.push((protocol, make_packet(code, id, answer)));
+}
+
+fn make_packet(code: u8, id: u8, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![code, id];
+    packet.extend_from_slice(&(data.len() as u16 + 4).to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// Splits a control packet into its code, identifier and data, without the padding.
+fn parse_packet(packet: &[u8]) -> Option<(u8, u8, &[u8])> {
+    let &[code, id, a, b, ..] = packet else {
+        return None;
+    };
+    let len = u16::from_be_bytes([a, b]) as usize;
+    (4..=packet.len())
+        .contains(&len)
+        .then(|| (code, id, &packet[4..len]))
+}
+
+/// Splits the data of a Configure packet into its options, if it's well formed.
+fn options(mut data: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut options = Vec::new();
+    while let &[kind, len, ..] = data {
+        let len = len as usize;
+        if len < 2 || len > data.len() {
+            return None;
+        }
+        options.push((kind, &data[2..len]));
+        data = &data[len..];
+    }
+    data.is_empty().then_some(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 1, 0, 1);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(10, 1, 0, 2);
+
+    /// The packets that went out since last time, with their protocol, code, id and data.
+    fn sent(ppp: &mut Ppp) -> Vec<(u16, u8, u8, Vec<u8>)> {
+        ppp.replies
+            .drain(..)
+            .map(|(protocol, packet)| {
+                let (code, id, data) = parse_packet(&packet).unwrap();
+                (protocol, code, id, data.to_vec())
+            })
+            .collect()
+    }
+
+    fn option(kind: u8, value: &[u8]) -> Vec<u8> {
+        [&[kind, value.len() as u8 + 2][..], value].concat()
+    }
+
+    /// Our Configure-Request for `protocol`, which should be the only thing sent.
+    fn our_request(ppp: &mut Ppp, protocol: u16) -> (u8, Vec<u8>) {
+        match &sent(ppp)[..] {
+            [(p, CONFIGURE_REQUEST, id, data)] if *p == protocol => (*id, data.clone()),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    /// Brings LCP up with a peer that asks for nothing special.
+    fn open_lcp(ppp: &mut Ppp) {
+        ppp.tick();
+        let (id, _) = our_request(ppp, LCP);
+        let request = option(MAGIC_NUMBER, &[1, 2, 3, 4]);
+        ppp.handle(LCP, &make_packet(CONFIGURE_REQUEST, 7, &request))
+            .unwrap();
+        assert_eq!(sent(ppp), [(LCP, CONFIGURE_ACK, 7, request)]);
+        ppp.handle(LCP, &make_packet(CONFIGURE_ACK, id, &[]))
+            .unwrap();
+        assert!(ppp.lcp.is_open());
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut ppp = Ppp::new("test".into(), Some(LOCAL), Some(REMOTE), false);
+        open_lcp(&mut ppp);
+        assert!(!ppp.is_up());
+
+        // IPCP starts right away, asking for the interface's address
+        let (id, data) = our_request(&mut ppp, IPCP);
+        assert_eq!(data, option(IP_ADDRESS, &LOCAL.octets()));
+        ppp.handle(IPCP, &make_packet(CONFIGURE_ACK, id, &data))
+            .unwrap();
+
+        // the peer asks for an address, and gets the one it's supposed to have
+        let ask = option(IP_ADDRESS, &[0; 4]);
+        ppp.handle(IPCP, &make_packet(CONFIGURE_REQUEST, 1, &ask))
+            .unwrap();
+        let given = option(IP_ADDRESS, &REMOTE.octets());
+        assert_eq!(sent(&mut ppp), [(IPCP, CONFIGURE_NAK, 1, given.clone())]);
+        assert!(!ppp.is_up());
+        ppp.handle(IPCP, &make_packet(CONFIGURE_REQUEST, 2, &given))
+            .unwrap();
+        assert_eq!(sent(&mut ppp), [(IPCP, CONFIGURE_ACK, 2, given)]);
+        assert!(ppp.is_up());
+        assert_eq!(
+            ppp.rx(),
+            Params {
+                accm: 0,
+                fcs32: false
+            }
+        );
+    }
+
+    #[test]
+    fn address_from_peer() {
+        let mut ppp = Ppp::new("test".into(), None, None, false);
+        open_lcp(&mut ppp);
+        let (id, data) = our_request(&mut ppp, IPCP);
+        assert_eq!(data, option(IP_ADDRESS, &[0; 4]));
+
+        // the peer tells us where we are, and we ask for that
+        let given = option(IP_ADDRESS, &LOCAL.octets());
+        ppp.handle(IPCP, &make_packet(CONFIGURE_NAK, id, &given))
+            .unwrap();
+        let (id, data) = our_request(&mut ppp, IPCP);
+        assert_eq!(data, given);
+        ppp.handle(IPCP, &make_packet(CONFIGURE_ACK, id, &data))
+            .unwrap();
+
+        // without an address to give, the peer has to know its own
+        let ask = option(IP_ADDRESS, &[0; 4]);
+        ppp.handle(IPCP, &make_packet(CONFIGURE_REQUEST, 1, &ask))
+            .unwrap();
+        assert_eq!(sent(&mut ppp), [(IPCP, CONFIGURE_REJECT, 1, ask)]);
+        let own = option(IP_ADDRESS, &REMOTE.octets());
+        ppp.handle(IPCP, &make_packet(CONFIGURE_REQUEST, 2, &own))
+            .unwrap();
+        assert_eq!(sent(&mut ppp), [(IPCP, CONFIGURE_ACK, 2, own)]);
+        assert!(ppp.is_up());
+    }
+
+    #[test]
+    fn unknown_options_rejected() {
+        let mut ppp = Ppp::new("test".into(), Some(LOCAL), Some(REMOTE), false);
+        ppp.tick();
+        sent(&mut ppp);
+
+        // PAP and protocol field compression, among options we're fine with
+        let pap = option(3, &[0xc0, 0x23]);
+        let pfc = option(7, &[]);
+        let request = [option(MRU, &[5, 0xdc]), pap.clone(), pfc.clone()].concat();
+        ppp.handle(LCP, &make_packet(CONFIGURE_REQUEST, 1, &request))
+            .unwrap();
+        assert_eq!(
+            sent(&mut ppp),
+            [(LCP, CONFIGURE_REJECT, 1, [pap, pfc].concat())]
+        );
+
+        open_lcp(&mut ppp);
+        sent(&mut ppp);
+        // Van Jacobson compression
+        let vj = option(2, &[0, 0x2d, 15, 1]);
+        let request = [vj.clone(), option(IP_ADDRESS, &REMOTE.octets())].concat();
+        ppp.handle(IPCP, &make_packet(CONFIGURE_REQUEST, 3, &request))
+            .unwrap();
+        assert_eq!(sent(&mut ppp), [(IPCP, CONFIGURE_REJECT, 3, vj)]);
+
+        // and other protocols altogether
+        ppp.handle(0x8057, &make_packet(CONFIGURE_REQUEST, 1, &[]))
+            .unwrap();
+        match &sent(&mut ppp)[..] {
+            [(LCP, PROTOCOL_REJECT, _, data)] => assert!(data.starts_with(&[0x80, 0x57])),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn fcs32_refused() {
+        let mut ppp = Ppp::new("test".into(), Some(LOCAL), Some(REMOTE), true);
+        ppp.tick();
+        let (id, data) = our_request(&mut ppp, LCP);
+        let fcs = option(FCS_ALTERNATIVES, &[FCS_32]);
+        assert!(data.ends_with(&fcs));
+
+        ppp.handle(LCP, &make_packet(CONFIGURE_REJECT, id, &fcs))
+            .unwrap();
+        let (id, data) = our_request(&mut ppp, LCP);
+        assert!(!data.ends_with(&fcs));
+        ppp.handle(LCP, &make_packet(CONFIGURE_ACK, id, &data))
+            .unwrap();
+        ppp.handle(LCP, &make_packet(CONFIGURE_REQUEST, 1, &[]))
+            .unwrap();
+        assert!(ppp.lcp.is_open());
+        assert!(!ppp.rx().fcs32);
+    }
+
+    #[test]
+    fn terminate() {
+        let mut ppp = Ppp::new("test".into(), Some(LOCAL), Some(REMOTE), false);
+        open_lcp(&mut ppp);
+        sent(&mut ppp);
+        assert!(ppp
+            .handle(LCP, &make_packet(TERMINATE_REQUEST, 9, &[]))
+            .is_err());
+        assert_eq!(sent(&mut ppp), [(LCP, TERMINATE_ACK, 9, vec![])]);
+    }
+}
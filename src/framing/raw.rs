@@ -0,0 +1,325 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tracing::{info, trace, warn};
+
+use crate::fec::Fec;
+use crate::framing::{header_bytes, BadFrame, FrameReader, FrameWriter, MAX_PAYLOAD};
+use crate::stats::PeerStats;
+use crate::streams::PeerState;
+use crate::types::{Header, MARKER_SIZE, SYNC_MARKER};
+use crate::HEADER_SIZE;
+
+/// Frames sent back to back on a byte stream, found again by their sync marker after a desync.
+pub struct RawReader<R> {
+    stream: ReadHalf<R>,
+    fec: Option<Fec>,
+    path: String,
+    state: Arc<PeerState>,
+    fec_buf: Vec<u8>,
+    header_buf: [u8; HEADER_SIZE],
+    header: Option<Header>,
+    // bytes repaired by FEC in the current frame
+    corrected: usize,
+    desynced: bool,
+}
+
+impl<R> RawReader<R> {
+    pub fn new(stream: ReadHalf<R>, fec: Option<Fec>, path: String, state: Arc<PeerState>) -> Self {
+        Self {
+            stream,
+            fec,
+            path,
+            state,
+            fec_buf: Vec::new(),
+            header_buf: [0u8; HEADER_SIZE],
+            header: None,
+            corrected: 0,
+            desynced: false,
+        }
+    }
+}
+
+/// Reads the parity bytes following a header and repairs it in place.
+///
+/// Returns the number of repaired bytes, or `None` if the header is beyond repair.
+async fn repair_header<R>(
+    stream: &mut ReadHalf<R>,
+    header_buf: &mut [u8; HEADER_SIZE],
+    fec: &Fec,
+) -> anyhow::Result<Option<usize>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut block = Vec::with_capacity(HEADER_SIZE + fec.ecc_len());
+    block.extend_from_slice(header_buf);
+    block.resize(HEADER_SIZE + fec.ecc_len(), 0);
+    stream.read_exact(&mut block[HEADER_SIZE..]).await?;
+
+    let mut repaired = Vec::with_capacity(HEADER_SIZE);
+    let corrected = fec.decode(&block, &mut repaired);
+    if corrected.is_some() {
+        header_buf.copy_from_slice(&repaired);
+    }
+    Ok(corrected)
+}
+
+impl<R> FrameReader for RawReader<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    async fn read_frame(&mut self, payload: &mut Vec<u8>) -> anyhow::Result<Header> {
+        let stats = &self.state.stats;
+        let path = &self.path;
+        let stream = &mut self.stream;
+        let header_buf = &mut self.header_buf;
+
+        loop {
+            if self.desynced {
+                // packet is malformed, we need to resync to the next packet with marker
+                let mut skip: usize = 0;
+                loop {
+                    header_buf.copy_within(1.., 0);
+                    header_buf[header_buf.len() - 1] = stream.read_u8().await?;
+                    skip += 1;
+
+                    if header_buf[..MARKER_SIZE] == SYNC_MARKER {
+                        // found it
+                        if let Some(fec) = &self.fec {
+                            match repair_header(stream, header_buf, fec).await? {
+                                Some(n) => self.corrected = n,
+                                None => {
+                                    warn!("[{}] Found bad marker: header beyond repair", path);
+                                    continue;
+                                }
+                            }
+                        }
+                        // read header
+                        match Header::from_slice(header_buf) {
+                            Ok(e) => {
+                                self.header = Some(e);
+                                self.desynced = false;
+                                info!("[{}] Fixed desync, skipped {} bytes.", path, skip);
+                                break;
+                            }
+                            Err(e) => match BadFrame::from_header_error(&e) {
+                                BadFrame::Corrupt => warn!("[{}] Found bad marker: {}", path, e),
+                                bad => self.state.drop_frame(path, bad),
+                            },
+                        }
+                    }
+                }
+            }
+            if let Some(h) = self.header {
+                if h.packet_length as usize > MAX_PAYLOAD {
+                    warn!("[{}] Stream desync", path);
+                    self.desynced = true;
+                    continue;
+                }
+                self.header = None;
+                payload.clear();
+                if let Some(fec) = &self.fec {
+                    self.fec_buf
+                        .resize(fec.encoded_len(h.packet_length as usize), 0);
+                    stream.read_exact(&mut self.fec_buf).await?;
+                    match fec.decode(&self.fec_buf, payload) {
+                        Some(n) => self.corrected += n,
+                        None => {
+                            let n = PeerStats::bump(&stats.fec_uncorrectable_frames);
+                            warn!("[{}] Dropped frame beyond repair ({} so far)", path, n);
+                            self.desynced = true;
+                            continue;
+                        }
+                    }
+                } else {
+                    payload.resize(h.packet_length as usize, 0);
+                    stream.read_exact(payload).await?;
+                }
+                if h.checksum(payload) != h.crc {
+                    let n = PeerStats::bump(&stats.corrupt_frames);
+                    warn!("[{}] Dropped corrupt frame ({} so far)", path, n);
+                    self.desynced = true;
+                    continue;
+                }
+                if self.corrected > 0 {
+                    let n = PeerStats::bump(&stats.fec_corrected_frames);
+                    trace!(
+                        "[{}] Repaired {} bytes ({} frames so far)",
+                        path,
+                        self.corrected,
+                        n
+                    );
+                    self.corrected = 0;
+                }
+                return Ok(h);
+            } else {
+                stream.read_exact(header_buf).await?;
+                self.corrected = 0;
+                if let Some(fec) = &self.fec {
+                    match repair_header(stream, header_buf, fec).await? {
+                        Some(n) => self.corrected = n,
+                        None => {
+                            let n = PeerStats::bump(&stats.fec_uncorrectable_frames);
+                            warn!(
+                                "[{}] Stream desync: header beyond repair ({} so far)",
+                                path, n
+                            );
+                            self.desynced = true;
+                            continue;
+                        }
+                    }
+                }
+                match Header::from_slice(header_buf) {
+                    Ok(e) => self.header = Some(e),
+                    Err(e) => {
+                        match BadFrame::from_header_error(&e) {
+                            BadFrame::Corrupt => warn!("[{}] Stream desync: {}", path, e),
+                            bad => self.state.drop_frame(path, bad),
+                        }
+                        self.desynced = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct RawWriter<W> {
+    stream: WriteHalf<W>,
+    fec: Option<Fec>,
+    buf: Vec<u8>,
+}
+
+impl<W> RawWriter<W> {
+    pub fn new(stream: WriteHalf<W>, fec: Option<Fec>) -> Self {
+        Self {
+            stream,
+            fec,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<W> FrameWriter for RawWriter<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn write_frame(&mut self, header: Header, payload: &[u8]) -> anyhow::Result<()> {
+        let header_buf = header_bytes(header, payload);
+        if let Some(fec) = &self.fec {
+            // the header gets its own block, so it can be repaired before the payload
+            self.buf.clear();
+            fec.encode(&header_buf, &mut self.buf);
+            fec.encode(payload, &mut self.buf);
+            self.stream.write_all(&self.buf).await?;
+        } else {
+            self.stream.write_all(&header_buf).await?;
+            self.stream.write_all(payload).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::crypto::Crypto;
+    use crate::framing::encode_frame;
+    use crate::types::VERSION;
+
+    fn frame(header: Header, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![];
+        encode_frame(header, payload, &mut frame);
+        frame
+    }
+
+    /// Feeds `bytes` to a reader, returning the payloads it let through and the peer's state.
+    async fn read_all(fec: Option<Fec>, bytes: &[u8]) -> (Vec<Vec<u8>>, Arc<PeerState>) {
+        let state = Arc::new(PeerState::new(Crypto::None, None));
+        let (mut near, far) = tokio::io::duplex(4096);
+        let (read, _write) = tokio::io::split(far);
+        let mut reader = RawReader::new(read, fec, "test".to_string(), state.clone());
+
+        near.write_all(bytes).await.unwrap();
+        drop(near);
+
+        let mut payloads = vec![];
+        let mut payload = vec![];
+        while reader.read_frame(&mut payload).await.is_ok() {
+            payloads.push(payload.clone());
+        }
+        (payloads, state)
+    }
+
+    #[tokio::test]
+    async fn flipped_bit_is_dropped() {
+        let mut bad = frame(Header::default(), b"second");
+        bad[HEADER_SIZE + 2] ^= 0x10;
+        let bytes = [
+            frame(Header::default(), b"first"),
+            bad,
+            frame(Header::default(), b"third"),
+        ]
+        .concat();
+
+        let (payloads, state) = read_all(None, &bytes).await;
+        assert_eq!(payloads, [&b"first"[..], b"third"]);
+        assert_eq!(state.stats.corrupt_frames.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_length_is_dropped() {
+        let mut bad = frame(Header::default(), b"second");
+        bad[6..8].copy_from_slice(&5u16.to_le_bytes());
+        let bytes = [
+            frame(Header::default(), b"first"),
+            bad,
+            frame(Header::default(), b"third"),
+        ]
+        .concat();
+
+        let (payloads, state) = read_all(None, &bytes).await;
+        assert_eq!(payloads, [&b"first"[..], b"third"]);
+        assert_eq!(state.stats.corrupt_frames.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn other_version_is_counted_apart() {
+        let other = Header {
+            version: VERSION + 1,
+            ..Default::default()
+        };
+        let bytes = [
+            frame(other, b"first"),
+            frame(other, b"second"),
+            frame(Header::default(), b"third"),
+        ]
+        .concat();
+
+        let (payloads, state) = read_all(None, &bytes).await;
+        assert_eq!(payloads, [&b"third"[..]]);
+        assert_eq!(state.stats.other_version_frames.load(Ordering::Relaxed), 2);
+        assert_eq!(state.stats.corrupt_frames.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn damaged_header_is_repaired() {
+        let fec = Fec::new(4).unwrap();
+        let mut bytes = vec![];
+        for payload in [&b"first"[..], b"second"] {
+            let frame = frame(Header::default(), payload);
+            fec.encode(&frame[..HEADER_SIZE], &mut bytes);
+            fec.encode(&frame[HEADER_SIZE..], &mut bytes);
+        }
+        // the sync marker and length of the first frame
+        bytes[1] ^= 0xff;
+        bytes[6] ^= 0x01;
+
+        let (payloads, state) = read_all(Some(fec), &bytes).await;
+        assert_eq!(payloads, [&b"first"[..], b"second"]);
+        assert_eq!(state.stats.fec_corrected_frames.load(Ordering::Relaxed), 1);
+        assert_eq!(state.stats.corrupt_frames.load(Ordering::Relaxed), 0);
+    }
+}
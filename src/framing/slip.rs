@@ -0,0 +1,277 @@
+use std::io;
+use std::sync::Arc;
+
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+use tracing::warn;
+
+use crate::framing::vj::{Compressor, Decompressor};
+use crate::framing::{FrameReader, FrameWriter, MAX_PAYLOAD};
+use crate::stats::PeerStats;
+use crate::streams::PeerState;
+use crate::types::{FrameKind, Header};
+
+const END: u8 = 0xc0;
+const ESC: u8 = 0xdb;
+const ESC_END: u8 = 0xdc;
+const ESC_ESC: u8 = 0xdd;
+
+/// Bare IP packets between END bytes, as in RFC 1055.
+///
+/// There's no room for headers, so the packets can't be compressed or encrypted, and the
+/// IP checksums are all there is to catch line noise.
+pub struct SlipReader<R> {
+    stream: BufReader<ReadHalf<R>>,
+    vj: Option<Decompressor>,
+    path: String,
+    state: Arc<PeerState>,
+    frame: Vec<u8>,
+}
+
+impl<R: AsyncRead> SlipReader<R> {
+    pub fn new(stream: ReadHalf<R>, cslip: bool, path: String, state: Arc<PeerState>) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            vj: cslip.then(Decompressor::default),
+            path,
+            state,
+            frame: Vec::with_capacity(MAX_PAYLOAD),
+        }
+    }
+
+    /// Reads up to the next END, returns whether what came before it is intact.
+    async fn read_until_end(&mut self) -> anyhow::Result<bool> {
+        self.frame.clear();
+        let mut escaped = false;
+        let mut intact = true;
+        loop {
+            let buf = self.stream.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let mut used = 0;
+            let mut ended = false;
+            for &byte in buf {
+                used += 1;
+                let byte = match (escaped, byte) {
+                    (_, END) => {
+                        ended = true;
+                        break;
+                    }
+                    (false, ESC) => {
+                        escaped = true;
+                        continue;
+                    }
+                    (false, byte) => byte,
+                    (true, ESC_END) => END,
+                    (true, ESC_ESC) => ESC,
+                    (true, _) => {
+                        intact = false;
+                        ESC
+                    }
+                };
+                escaped = false;
+                if self.frame.len() < MAX_PAYLOAD {
+                    self.frame.push(byte);
+                } else {
+                    intact = false;
+                }
+            }
+            self.stream.consume(used);
+            if ended {
+                return Ok(intact && !escaped);
+            }
+        }
+    }
+}
+
+impl<R> FrameReader for SlipReader<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    async fn read_frame(&mut self, payload: &mut Vec<u8>) -> anyhow::Result<Header> {
+        loop {
+            let intact = self.read_until_end().await?;
+            // senders start frames with an END too, to flush any line noise
+            if intact && self.frame.is_empty() {
+                continue;
+            }
+            let restored = match (&mut self.vj, intact) {
+                (Some(vj), true) => vj.decompress(&self.frame, payload),
+                (Some(vj), false) => {
+                    vj.lost();
+                    false
+                }
+                (None, intact) => {
+                    payload.clear();
+                    payload.extend_from_slice(&self.frame);
+                    intact
+                }
+            };
+            // line noise that made it through without an END in it
+            let ip = matches!(payload.first().map(|b| b >> 4), Some(4 | 6));
+            if !restored || !ip {
+                let n = PeerStats::bump(&self.state.stats.corrupt_frames);
+                warn!("[{}] Dropped corrupt frame ({} so far)", self.path, n);
+                continue;
+            }
+
+            return Ok(Header {
+                packet_length: payload.len() as u16,
+                ..Default::default()
+            });
+        }
+    }
+}
+
+pub struct SlipWriter<W> {
+    stream: WriteHalf<W>,
+    vj: Option<Compressor>,
+    packet: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<W> SlipWriter<W> {
+    pub fn new(stream: WriteHalf<W>, cslip: bool) -> Self {
+        Self {
+            stream,
+            vj: cslip.then(Compressor::default),
+            packet: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<W> FrameWriter for SlipWriter<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn write_frame(&mut self, header: Header, payload: &[u8]) -> anyhow::Result<()> {
+        // there's nowhere to put anything but packets
+        if header.kind != FrameKind::Data {
+            return Ok(());
+        }
+        let packet = match &mut self.vj {
+            Some(vj) => {
+                vj.compress(payload, &mut self.packet);
+                &self.packet[..]
+            }
+            None => payload,
+        };
+
+        self.buf.clear();
+        self.buf.push(END);
+        for &byte in packet {
+            match byte {
+                END => self.buf.extend_from_slice(&[ESC, ESC_END]),
+                ESC => self.buf.extend_from_slice(&[ESC, ESC_ESC]),
+                byte => self.buf.push(byte),
+            }
+        }
+        self.buf.push(END);
+        self.stream.write_all(&self.buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use tokio::io::{duplex, split, DuplexStream};
+
+    use super::*;
+    use crate::crypto::Crypto;
+    use crate::framing::vj::tests::connection;
+
+    fn pair(cslip: bool) -> (SlipReader<DuplexStream>, SlipWriter<DuplexStream>) {
+        let (a, b) = duplex(4096);
+        let state = Arc::new(PeerState::new(Crypto::None, None));
+        let reader = SlipReader::new(split(a).0, cslip, "test".into(), state);
+        (reader, SlipWriter::new(split(b).1, cslip))
+    }
+
+    fn data(payload: &[u8]) -> Header {
+        Header {
+            packet_length: payload.len() as u16,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn escaping() {
+        let (mut reader, mut writer) = pair(false);
+        let packets: [&[u8]; 4] = [
+            &[0x45, 1, 2, 3],
+            &[0x45, END, ESC, END, END, ESC_END, ESC_ESC],
+            &[0x60, ESC, ESC, ESC],
+            &[0x45, ESC, ESC_END, END, ESC_ESC, END],
+        ];
+        let mut payload = Vec::new();
+        for packet in packets {
+            writer.write_frame(data(packet), packet).await.unwrap();
+            let header = reader.read_frame(&mut payload).await.unwrap();
+            assert_eq!(payload, packet);
+            assert_eq!(header.packet_length as usize, packet.len());
+        }
+        assert_eq!(reader.state.stats.corrupt_frames.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn noise_is_dropped() {
+        let (mut reader, mut writer) = pair(false);
+        let packet = [0x45, END, 7];
+        // not IP, and an escape of nothing in particular
+        writer
+            .stream
+            .write_all(&[END, 0x12, 0x34, END])
+            .await
+            .unwrap();
+        writer
+            .stream
+            .write_all(&[0x45, ESC, 0x01, END])
+            .await
+            .unwrap();
+        writer.write_frame(data(&packet), &packet).await.unwrap();
+
+        let mut payload = Vec::new();
+        reader.read_frame(&mut payload).await.unwrap();
+        assert_eq!(payload, packet);
+        assert_eq!(reader.state.stats.corrupt_frames.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn cslip_resync() {
+        let (mut reader, mut writer) = pair(true);
+        let packets = connection();
+        let mut payload = Vec::new();
+        for packet in &packets[..10] {
+            writer.write_frame(data(packet), packet).await.unwrap();
+            reader.read_frame(&mut payload).await.unwrap();
+            assert_eq!(&payload, packet);
+        }
+
+        // packets[10] gets damaged on the line, and the ones after it can't be restored
+        writer
+            .vj
+            .as_mut()
+            .unwrap()
+            .compress(&packets[10], &mut writer.packet);
+        writer
+            .stream
+            .write_all(&[END, ESC, 0x01, END])
+            .await
+            .unwrap();
+        for packet in &packets[11..13] {
+            writer.write_frame(data(packet), packet).await.unwrap();
+        }
+        // until TCP retransmits it, which goes out as TYPE_UNCOMPRESSED_TCP
+        for packet in &packets[10..] {
+            writer.write_frame(data(packet), packet).await.unwrap();
+            reader.read_frame(&mut payload).await.unwrap();
+            assert_eq!(&payload, packet);
+        }
+        assert_eq!(reader.state.stats.corrupt_frames.load(Ordering::Relaxed), 3);
+    }
+}
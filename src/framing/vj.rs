@@ -0,0 +1,499 @@
+//! Van Jacobson TCP/IP header compression (RFC 1144), as used by CSLIP.
+//!
+//! Each side remembers the last headers of a few TCP connections, so that most segments only
+//! need to carry what changed in their headers.
+
+/// Connections remembered on each side, like in Linux.
+pub const SLOTS: usize = 16;
+
+const TYPE_UNCOMPRESSED_TCP: u8 = 0x70;
+const TYPE_COMPRESSED_TCP: u8 = 0x80;
+
+// what a compressed header carries
+const NEW_C: u8 = 0x40;
+const NEW_I: u8 = 0x20;
+const PUSH: u8 = 0x10;
+const NEW_S: u8 = 0x08;
+const NEW_A: u8 = 0x04;
+const NEW_W: u8 = 0x02;
+const NEW_U: u8 = 0x01;
+// combinations that can't happen otherwise, used for the most common segments
+const SPECIAL_I: u8 = NEW_S | NEW_W | NEW_U;
+const SPECIAL_D: u8 = NEW_S | NEW_A | NEW_W | NEW_U;
+const SPECIALS_MASK: u8 = SPECIAL_D;
+
+const TCP: u8 = 6;
+
+// TCP flags
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+const URG: u8 = 0x20;
+
+/// Compresses the headers of outgoing TCP/IPv4 segments.
+#[derive(Default)]
+pub struct Compressor {
+    slots: Vec<Slot>,
+    /// slot of the last segment sent, which the next one doesn't have to repeat
+    last: Option<usize>,
+    clock: u64,
+}
+
+struct Slot {
+    header: Vec<u8>,
+    used: u64,
+}
+
+impl Compressor {
+    /// Puts `packet` into `out`, with its headers compressed if it's a TCP segment.
+    pub fn compress(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        let Some((ihl, hlen)) = header_lens(packet).filter(|_| is_compressible(packet)) else {
+            out.extend_from_slice(packet);
+            return;
+        };
+        self.clock += 1;
+
+        let found = self.slots.iter().position(|s| {
+            let s_ihl = ip_header_len(&s.header);
+            s.header[12..20] == packet[12..20] && s.header[s_ihl..s_ihl + 4] == packet[ihl..ihl + 4]
+        });
+        let index = match found {
+            Some(index) => index,
+            None if self.slots.len() < SLOTS => {
+                self.slots.push(Slot {
+                    header: Vec::new(),
+                    used: 0,
+                });
+                return self.uncompressed(self.slots.len() - 1, packet, hlen, out);
+            }
+            None => {
+                let lru = (0..self.slots.len())
+                    .min_by_key(|&i| self.slots[i].used)
+                    .unwrap_or(0);
+                return self.uncompressed(lru, packet, hlen, out);
+            }
+        };
+
+        let old = &self.slots[index].header;
+        let (th, old_th) = (&packet[ihl..hlen], &old[ihl.min(old.len())..]);
+        // anything that isn't sent as a delta has to be the same as last time
+        if old.len() != hlen
+            || old[..2] != packet[..2]
+            || old[6..10] != packet[6..10]
+            || old[20..ihl] != packet[20..ihl]
+            || old_th[12] != th[12]
+            || (old_th[13] ^ th[13]) & !(PSH | URG) != 0
+            || old_th[20..] != th[20..]
+        {
+            return self.uncompressed(index, packet, hlen, out);
+        }
+
+        let mut deltas = Vec::with_capacity(16);
+        let mut changes = 0;
+        if th[13] & URG != 0 {
+            encode(&mut deltas, be16(th, 18));
+            changes |= NEW_U;
+        } else if be16(th, 18) != be16(old_th, 18) {
+            return self.uncompressed(index, packet, hlen, out);
+        }
+        let window = be16(th, 14).wrapping_sub(be16(old_th, 14));
+        if window != 0 {
+            encode(&mut deltas, window);
+            changes |= NEW_W;
+        }
+        let ack = be32(th, 8).wrapping_sub(be32(old_th, 8));
+        if ack != 0 {
+            if ack > 0xffff {
+                return self.uncompressed(index, packet, hlen, out);
+            }
+            encode(&mut deltas, ack as u16);
+            changes |= NEW_A;
+        }
+        let seq = be32(th, 4).wrapping_sub(be32(old_th, 4));
+        if seq != 0 {
+            if seq > 0xffff {
+                return self.uncompressed(index, packet, hlen, out);
+            }
+            encode(&mut deltas, seq as u16);
+            changes |= NEW_S;
+        }
+
+        let old_data = (be16(old, 2) as u32).wrapping_sub(hlen as u32);
+        match changes {
+            // data after a bare ack, as on interactive connections, otherwise most likely a
+            // retransmission
+            0 if be16(packet, 2) != be16(old, 2) && old_data == 0 => {}
+            0 | SPECIAL_I | SPECIAL_D => return self.uncompressed(index, packet, hlen, out),
+            // echoed terminal traffic
+            c if c == NEW_S | NEW_A && seq == ack && seq == old_data => {
+                changes = SPECIAL_I;
+                deltas.clear();
+            }
+            // bulk transfer
+            NEW_S if seq == old_data => {
+                changes = SPECIAL_D;
+                deltas.clear();
+            }
+            _ => {}
+        }
+        let id = be16(packet, 4).wrapping_sub(be16(old, 4));
+        if id != 1 {
+            encode(&mut deltas, id);
+            changes |= NEW_I;
+        }
+        if th[13] & PSH != 0 {
+            changes |= PUSH;
+        }
+
+        if self.last == Some(index) {
+            out.push(TYPE_COMPRESSED_TCP | changes);
+        } else {
+            out.extend_from_slice(&[TYPE_COMPRESSED_TCP | NEW_C | changes, index as u8]);
+            self.last = Some(index);
+        }
+        out.extend_from_slice(&th[16..18]);
+        out.extend_from_slice(&deltas);
+        out.extend_from_slice(&packet[hlen..]);
+
+        let slot = &mut self.slots[index];
+        slot.header.clear();
+        slot.header.extend_from_slice(&packet[..hlen]);
+        slot.used = self.clock;
+    }
+
+    /// Sends the whole segment, with the slot it's remembered in instead of the protocol.
+    fn uncompressed(&mut self, index: usize, packet: &[u8], hlen: usize, out: &mut Vec<u8>) {
+        let slot = &mut self.slots[index];
+        slot.header.clear();
+        slot.header.extend_from_slice(&packet[..hlen]);
+        slot.used = self.clock;
+        self.last = Some(index);
+
+        out.extend_from_slice(packet);
+        out[0] = TYPE_UNCOMPRESSED_TCP | (packet[0] & 0x0f);
+        out[9] = index as u8;
+    }
+}
+
+/// Restores the headers of incoming TCP/IPv4 segments.
+pub struct Decompressor {
+    slots: Vec<Option<Vec<u8>>>,
+    last: Option<usize>,
+    /// whether segments are being dropped until the other end resends a whole header
+    toss: bool,
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self {
+            slots: vec![None; SLOTS],
+            last: None,
+            toss: false,
+        }
+    }
+}
+
+impl Decompressor {
+    /// Called when a frame got lost, since the next ones may depend on it.
+    pub fn lost(&mut self) {
+        self.toss = true;
+    }
+
+    /// Puts the packet `frame` was made from into `out`.
+    ///
+    /// Returns false when it can't be restored, and has to be dropped.
+    pub fn decompress(&mut self, frame: &[u8], out: &mut Vec<u8>) -> bool {
+        out.clear();
+        let restored = match frame.first() {
+            Some(b) if b & TYPE_COMPRESSED_TCP != 0 => self.compressed(frame, out),
+            Some(b) if b & 0xf0 == TYPE_UNCOMPRESSED_TCP => self.uncompressed(frame, out),
+            _ => {
+                out.extend_from_slice(frame);
+                Some(())
+            }
+        };
+        if restored.is_none() {
+            self.toss = true;
+        }
+        restored.is_some()
+    }
+
+    fn uncompressed(&mut self, frame: &[u8], out: &mut Vec<u8>) -> Option<()> {
+        let index = *frame.get(9)? as usize;
+        if index >= SLOTS {
+            return None;
+        }
+        out.extend_from_slice(frame);
+        out[0] = 0x40 | (frame[0] & 0x0f);
+        out[9] = TCP;
+        let (_, hlen) = header_lens(out)?;
+
+        self.slots[index] = Some(out[..hlen].to_vec());
+        self.last = Some(index);
+        self.toss = false;
+        Some(())
+    }
+
+    fn compressed(&mut self, frame: &[u8], out: &mut Vec<u8>) -> Option<()> {
+        let changes = frame[0];
+        let mut deltas = Deltas { frame, pos: 1 };
+        if changes & NEW_C != 0 {
+            let index = deltas.byte()? as usize;
+            if index >= SLOTS {
+                return None;
+            }
+            self.last = Some(index);
+            self.toss = false;
+        } else if self.toss {
+            return None;
+        }
+        let header = self.slots[self.last?].as_deref_mut()?;
+        let ihl = ip_header_len(header);
+        let hlen = header.len();
+        let th = &mut header[ihl..];
+
+        let checksum = deltas.bytes(2)?;
+        th[16..18].copy_from_slice(checksum);
+        if changes & PUSH != 0 {
+            th[13] |= PSH;
+        } else {
+            th[13] &= !PSH;
+        }
+
+        let old_data = (be16(header, 2) as u32).wrapping_sub(hlen as u32);
+        let th = &mut header[ihl..];
+        match changes & SPECIALS_MASK {
+            SPECIAL_I => {
+                set_be32(th, 8, be32(th, 8).wrapping_add(old_data));
+                set_be32(th, 4, be32(th, 4).wrapping_add(old_data));
+            }
+            SPECIAL_D => set_be32(th, 4, be32(th, 4).wrapping_add(old_data)),
+            _ => {
+                if changes & NEW_U != 0 {
+                    th[13] |= URG;
+                    set_be16(th, 18, deltas.delta()?);
+                } else {
+                    th[13] &= !URG;
+                }
+                if changes & NEW_W != 0 {
+                    set_be16(th, 14, be16(th, 14).wrapping_add(deltas.delta()?));
+                }
+                if changes & NEW_A != 0 {
+                    set_be32(th, 8, be32(th, 8).wrapping_add(deltas.delta()? as u32));
+                }
+                if changes & NEW_S != 0 {
+                    set_be32(th, 4, be32(th, 4).wrapping_add(deltas.delta()? as u32));
+                }
+            }
+        }
+        let id = match changes & NEW_I {
+            0 => 1,
+            _ => deltas.delta()?,
+        };
+        set_be16(header, 4, be16(header, 4).wrapping_add(id));
+
+        let data = &frame[deltas.pos..];
+        let len = u16::try_from(hlen + data.len()).ok()?;
+        set_be16(header, 2, len);
+        set_be16(header, 10, 0);
+        let checksum = ip_checksum(&header[..ihl]);
+        set_be16(header, 10, checksum);
+
+        out.extend_from_slice(header);
+        out.extend_from_slice(data);
+        Some(())
+    }
+}
+
+/// The fields of a compressed header that follow its first byte.
+struct Deltas<'a> {
+    frame: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Deltas<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.frame.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn delta(&mut self) -> Option<u16> {
+        match self.byte()? {
+            0 => Some(be16(self.bytes(2)?, 0)),
+            n => Some(n as u16),
+        }
+    }
+}
+
+/// Deltas fit in a byte, unless they're 0 or too big, then they take 3.
+fn encode(out: &mut Vec<u8>, delta: u16) {
+    if delta == 0 || delta > 255 {
+        out.push(0);
+        out.extend_from_slice(&delta.to_be_bytes());
+    } else {
+        out.push(delta as u8);
+    }
+}
+
+/// Whether a packet is a TCP segment that the other end can rebuild from a compressed header.
+fn is_compressible(packet: &[u8]) -> bool {
+    let ihl = ip_header_len(packet);
+    packet[0] >> 4 == 4
+        && packet[9] == TCP
+        // not a fragment
+        && be16(packet, 6) & 0x3fff == 0
+        && be16(packet, 2) as usize == packet.len()
+        && packet[ihl + 13] & (SYN | FIN | RST | ACK) == ACK
+}
+
+/// Lengths of the IP header, and of the IP and TCP headers together.
+fn header_lens(packet: &[u8]) -> Option<(usize, usize)> {
+    let ihl = ip_header_len(packet.get(..20)?);
+    let doff = (*packet.get(ihl + 12)? >> 4) as usize * 4;
+    (ihl >= 20 && doff >= 20 && ihl + doff <= packet.len()).then_some((ihl, ihl + doff))
+}
+
+fn ip_header_len(header: &[u8]) -> usize {
+    (header[0] & 0x0f) as usize * 4
+}
+
+fn ip_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2).map(|w| be16(w, 0) as u32).sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn be16(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+fn be32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn set_be16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at..at + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn set_be32(buf: &mut [u8], at: usize, value: u32) {
+    buf[at..at + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A TCP/IPv4 segment with a valid IP checksum.
+    pub(crate) fn segment(id: u16, seq: u32, ack: u32, window: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 40];
+        packet[0] = 0x45;
+        set_be16(&mut packet, 2, (40 + data.len()) as u16);
+        set_be16(&mut packet, 4, id);
+        set_be16(&mut packet, 6, 0x4000);
+        packet[8] = 64;
+        packet[9] = TCP;
+        packet[12..20].copy_from_slice(&[10, 1, 0, 1, 10, 1, 0, 2]);
+        let checksum = ip_checksum(&packet[..20]);
+        set_be16(&mut packet, 10, checksum);
+        let th = &mut packet[20..];
+        set_be16(th, 0, 40000);
+        set_be16(th, 2, 22);
+        set_be32(th, 4, seq);
+        set_be32(th, 8, ack);
+        th[12] = 0x50;
+        th[13] = ACK | if data.is_empty() { 0 } else { PSH };
+        set_be16(th, 14, window);
+        // carried as is
+        set_be16(th, 16, seq as u16 ^ 0x5a5a);
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    /// Segments of a connection whose seq, ack and window move along.
+    pub(crate) fn connection() -> Vec<Vec<u8>> {
+        let (mut seq, mut ack, mut window) = (0xfffff000, 1000, 64240);
+        (0..40u16)
+            .map(|i| {
+                let data = vec![i as u8; (i as usize * 37) % 300];
+                let packet = segment(100 + i, seq, ack, window, &data);
+                seq = seq.wrapping_add(data.len() as u32);
+                if i % 3 == 0 {
+                    ack += 500;
+                }
+                if i % 7 == 0 {
+                    window -= 1000;
+                }
+                packet
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut compressor, mut decompressor) = (Compressor::default(), Decompressor::default());
+        let (mut frame, mut out) = (Vec::new(), Vec::new());
+        for (i, packet) in connection().iter().enumerate() {
+            compressor.compress(packet, &mut frame);
+            if i > 0 {
+                assert_eq!(frame[0] & TYPE_COMPRESSED_TCP, TYPE_COMPRESSED_TCP, "{}", i);
+                assert!(frame.len() < packet.len() - 20, "{}", i);
+            }
+            assert!(decompressor.decompress(&frame, &mut out), "{}", i);
+            assert_eq!(&out, packet, "{}", i);
+        }
+    }
+
+    #[test]
+    fn other_packets_go_through() {
+        let (mut compressor, mut decompressor) = (Compressor::default(), Decompressor::default());
+        let (mut frame, mut out) = (Vec::new(), Vec::new());
+        let mut udp = segment(1, 0, 0, 0, b"hi");
+        udp[9] = 17;
+        compressor.compress(&udp, &mut frame);
+        assert_eq!(frame, udp);
+        assert!(decompressor.decompress(&frame, &mut out));
+        assert_eq!(out, udp);
+    }
+
+    #[test]
+    fn resync_after_loss() {
+        let (mut compressor, mut decompressor) = (Compressor::default(), Decompressor::default());
+        let (mut frame, mut out) = (Vec::new(), Vec::new());
+        let packets = connection();
+        for packet in &packets[..10] {
+            compressor.compress(packet, &mut frame);
+            assert!(decompressor.decompress(&frame, &mut out));
+        }
+
+        // the frame of packets[10] gets lost on the line
+        compressor.compress(&packets[10], &mut frame);
+        decompressor.lost();
+        // the next ones are deltas against it, and can't be restored
+        for packet in &packets[11..13] {
+            compressor.compress(packet, &mut frame);
+            assert!(!decompressor.decompress(&frame, &mut out));
+        }
+
+        // TCP retransmits, which goes out whole since its seq went back
+        compressor.compress(&packets[10], &mut frame);
+        assert_eq!(frame[0] & 0xf0, TYPE_UNCOMPRESSED_TCP);
+        assert!(decompressor.decompress(&frame, &mut out));
+        assert_eq!(out, packets[10]);
+        for packet in &packets[11..] {
+            compressor.compress(packet, &mut frame);
+            assert_eq!(frame[0] & TYPE_COMPRESSED_TCP, TYPE_COMPRESSED_TCP);
+            assert!(decompressor.decompress(&frame, &mut out));
+            assert_eq!(&out, packet);
+        }
+    }
+}
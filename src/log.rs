@@ -0,0 +1,111 @@
+//! Where logs go: stderr, or once detached from the terminal, a file or syslog.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use tracing::level_filters::LevelFilter;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::format::{DefaultFields, Format};
+use tracing_subscriber::fmt::{MakeWriter, SubscriberBuilder};
+
+/// The log format, whatever the output.
+pub fn builder(level: LevelFilter) -> SubscriberBuilder<DefaultFields, Format, LevelFilter> {
+    tracing_subscriber::fmt()
+        .event_format(
+            tracing_subscriber::fmt::format()
+                .with_file(true)
+                .with_line_number(true),
+        )
+        .with_max_level(level)
+}
+
+/// Logs of a daemon, which has no stderr anymore.
+pub enum Output {
+    /// Appended to a file.
+    File(File),
+    /// Sent to syslog, where journald picks them up as well.
+    Syslog,
+}
+
+impl Output {
+    /// Opens the log file, or syslog without one.
+    pub fn open(file: Option<&Path>) -> io::Result<Self> {
+        if let Some(path) = file {
+            return Ok(Output::File(
+                File::options().create(true).append(true).open(path)?,
+            ));
+        }
+
+        unsafe {
+            libc::openlog(
+                c"ip2char".as_ptr(),
+                libc::LOG_PID | libc::LOG_NDELAY,
+                libc::LOG_DAEMON,
+            )
+        };
+        Ok(Output::Syslog)
+    }
+}
+
+/// Writes one log line.
+pub enum Writer<'a> {
+    File(&'a File),
+    /// With the priority of the line.
+    Syslog(libc::c_int),
+}
+
+impl<'a> MakeWriter<'a> for Output {
+    type Writer = Writer<'a>;
+
+    fn make_writer(&'a self) -> Writer<'a> {
+        match self {
+            Output::File(file) => Writer::File(file),
+            Output::Syslog => Writer::Syslog(libc::LOG_INFO),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Writer<'a> {
+        match self.make_writer() {
+            Writer::Syslog(_) => Writer::Syslog(priority(meta.level())),
+            writer => writer,
+        }
+    }
+}
+
+fn priority(level: &Level) -> libc::c_int {
+    match *level {
+        Level::ERROR => libc::LOG_ERR,
+        Level::WARN => libc::LOG_WARNING,
+        Level::INFO => libc::LOG_INFO,
+        _ => libc::LOG_DEBUG,
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::File(file) => file.write(buf),
+            Writer::Syslog(priority) => {
+                // the formatter hands over whole lines, syslog adds its own newline
+                let line: Vec<u8> = buf
+                    .trim_ascii_end()
+                    .iter()
+                    .copied()
+                    .filter(|&b| b != 0)
+                    .collect();
+                let line = CString::new(line).unwrap_or_default();
+                unsafe { libc::syslog(*priority, c"%s".as_ptr(), line.as_ptr()) };
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::File(file) => file.flush(),
+            Writer::Syslog(_) => Ok(()),
+        }
+    }
+}
@@ -1,22 +1,55 @@
+mod backoff;
+mod cli;
 mod compression;
 mod config;
+mod crypto;
+mod fec;
+mod filter;
+mod framing;
+mod log;
+mod noise;
 mod packet_handling;
+mod replay;
+mod routing;
+mod stats;
 mod streams;
 mod transport;
 mod tun_device;
 mod types;
+mod usb;
 mod utils;
+mod validation;
 
-use crate::config::parse_config;
+use crate::backoff::Backoff;
+use crate::cli::{Cli, Command};
+use crate::config::{parse_config, Config};
+use crate::crypto::Crypto;
+use crate::filter::Filter;
 use crate::packet_handling::{handle_packet_from_kernel, prep_packet_for_kernel};
+use crate::routing::Router;
+use crate::streams::{LinkState, PeerState};
 use crate::transport::char::connect_serial;
+use crate::transport::exec::connect_exec;
+use crate::transport::listener::Listeners;
 use crate::transport::sock::{connect_sock, connect_sock_listen};
+use crate::transport::stdio::connect_stdio;
+use crate::transport::tls::{connect_tls, connect_tls_listen};
+use crate::transport::udp::connect_udp;
+use crate::transport::unix::{connect_unix, connect_unix_listen};
+use crate::transport::websocket::{connect_ws, connect_ws_listen};
 use bytes::Bytes;
+use clap::Parser;
 use config::Peer;
 use futures::{SinkExt, StreamExt};
-use packet::ip::v4::Packet;
+use ipnetwork::IpNetwork;
+use stats::PeerStats;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::select;
-use tokio::sync::{broadcast, mpsc};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, OnceCell};
+use tokio::task::JoinSet;
 use tracing::{error, info, warn};
 use tun_device::create_tun;
 use types::{Header, PostCommand};
@@ -24,46 +57,180 @@ use types::{Header, PostCommand};
 const HEADER_SIZE: usize = std::mem::size_of::<Header>();
 const MTU: usize = 1500;
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt()
-        .event_format(
-            tracing_subscriber::fmt::format()
-                .with_file(true)
-                .with_line_number(true),
-        )
-        .init();
-
-    match run().await {
-        Ok(_) => info!("ip2char exited successfully."),
-        Err(e) => error!("{}", e),
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // stdout may be carrying a peer-stdio link
+    let stderr = tracing::subscriber::set_default(
+        log::builder(cli.log_level)
+            .with_writer(std::io::stderr)
+            .finish(),
+    );
+
+    let path = cli.config_path();
+    let (config, all_peers, states) = match load(&path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("{}: {}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let foreground = match cli.command {
+        Some(Command::Check { .. }) => {
+            info!("{} is valid.", path.display());
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::Up { foreground, .. }) => foreground,
+        None => true,
+    };
+
+    let foreground = foreground || {
+        let stdio = all_peers.iter().any(|p| matches!(p, Peer::Stdio(_)));
+        if stdio {
+            info!("Staying in the foreground, peer-stdio needs stdin and stdout.");
+        }
+        stdio
+    };
+
+    // stderr goes away along with the terminal
+    let output = match foreground {
+        true => None,
+        false => match log::Output::open(cli.log_file.as_deref()) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                error!("Couldn't open the log file: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    // forking has to happen before the runtime spawns its threads
+    if output.is_some() {
+        if let Err(e) = daemonize() {
+            error!("Couldn't detach from the terminal: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    drop(stderr);
+    match output {
+        Some(output) => log::builder(cli.log_level)
+            .with_ansi(false)
+            .with_writer(output)
+            .init(),
+        None => log::builder(cli.log_level)
+            .with_writer(std::io::stderr)
+            .init(),
+    }
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(r) => r,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let res = runtime.block_on(run(config, all_peers, states));
+    // a blocking read on stdin would otherwise keep the runtime from shutting down
+    runtime.shutdown_timeout(Duration::from_secs(1));
+
+    match res {
+        Ok(_) => {
+            info!("ip2char exited successfully.");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
     }
 }
 
-async fn run() -> anyhow::Result<()> {
-    let (config, all_peers) = parse_config().await?;
-    let mut framed = create_tun(&config)?;
-    if let Some(down) = &config.interface.post_down {
-        tokio::spawn(utils::handle_post_down_command_sigint(down.to_string()));
+/// Parses the config and sets up the state of every peer, without touching the system.
+fn load(path: &std::path::Path) -> anyhow::Result<(Config, Vec<Peer>, Vec<Arc<PeerState>>)> {
+    let (config, all_peers) = parse_config(path)?;
+    let address = match config.interface.address {
+        IpNetwork::V4(address) => Some(address.ip()),
+        IpNetwork::V6(_) => None,
+    };
+    let mut states = Vec::with_capacity(all_peers.len());
+    for peer in all_peers.iter() {
+        states.push(Arc::new(PeerState::new(
+            Crypto::new(peer, config.interface.privatekey.as_ref())?,
+            address,
+        )));
+    }
+
+    Ok((config, all_peers, states))
+}
+
+fn daemonize() -> std::io::Result<()> {
+    // keep the working directory, relative paths in the config would break otherwise
+    if unsafe { libc::daemon(1, 0) } == -1 {
+        return Err(std::io::Error::last_os_error());
     }
+
+    Ok(())
+}
+
+async fn run(
+    config: Config,
+    all_peers: Vec<Peer>,
+    states: Vec<Arc<PeerState>>,
+) -> anyhow::Result<()> {
+    let filter = Filter::new(&config.interface);
+    let mut framed = create_tun(&config)?;
     let _cmd = PostCommand::new(config.interface.post_up, config.interface.post_down);
 
-    let (mpsc_tx, mut mpsc_rx) = mpsc::channel(config.interface.buffer.unwrap_or(512));
-    let (broadcast_tx, broadcast_rx) = broadcast::channel(config.interface.buffer.unwrap_or(512));
+    let buffer = config.interface.buffer.unwrap_or(512);
+    let (mpsc_tx, mut mpsc_rx) = mpsc::channel(buffer);
+    let mut router = Router::default();
 
-    for peer in all_peers.iter() {
-        tokio::task::spawn(connect_to_peer(
+    let listeners = Arc::new(Listeners::new(&all_peers, &states));
+    // stdio peers can't reconnect, the tunnel goes down with them
+    let mut stdio = JoinSet::new();
+    for (peer, state) in all_peers.iter().zip(&states) {
+        let (packet_tx, packet_rx) = mpsc::channel(buffer);
+        router.add_peer(peer, packet_tx, state.clone());
+        let task = connect_to_peer(
             peer.clone(),
-            broadcast_rx.resubscribe(),
+            packet_rx,
             mpsc_tx.clone(),
-        ));
+            state.clone(),
+            listeners.clone(),
+        );
+        if let Peer::Stdio(_) = peer {
+            stdio.spawn(task);
+        } else {
+            tokio::task::spawn(task);
+        }
     }
 
+    // dump statistics on SIGUSR1
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    // stop cleanly, so that post-down runs and the peers clean up after themselves
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
     loop {
         select! {
+            Some(()) = interrupt.recv() => break,
+            Some(()) = terminate.recv() => break,
+            Some(_) = stdio.join_next() => {
+                info!("stdin was closed.");
+                break;
+            },
+            Some(()) = usr1.recv() => {
+                info!("{}", filter.stats);
+                for (peer, state) in all_peers.iter().zip(&states) {
+                    info!("[{}] link {}, {}", peer.path(), state.link(), state.stats);
+                }
+            },
             Some(pkt) = framed.next() => {
                 match pkt {
-                    Ok(p) => handle_packet_from_kernel(p.into_bytes(), &broadcast_tx)?,
+                    Ok(p) => handle_packet_from_kernel(p.into_bytes(), &filter, &router)?,
                     Err(e) => warn!("{}", e)
                 }
 
@@ -71,7 +238,9 @@ async fn run() -> anyhow::Result<()> {
             Some(data) = mpsc_rx.recv() => {
                 if !data.is_empty() {
                     match prep_packet_for_kernel(data) {
-                        Ok(packet) => framed.send(packet).await?,
+                        Ok(packet) => if let Err(e) = framed.send(packet).await {
+                            warn!("Couldn't hand a packet to the kernel: {}", e);
+                        },
                         Err(e) => warn!("{}", e)
                     }
 
@@ -79,23 +248,135 @@ async fn run() -> anyhow::Result<()> {
             }
         };
     }
+
+    info!("Shutting down.");
+    Ok(())
 }
 
+/// Keeps a peer connected, reconnecting with exponential backoff whenever the link goes down.
 async fn connect_to_peer(
     peer: Peer,
-    broadcast_rx: broadcast::Receiver<Packet<Bytes>>,
+    mut packet_rx: mpsc::Receiver<Bytes>,
     mspc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+    listeners: Arc<Listeners>,
 ) {
-    info!("Connecting to {}...", peer.path());
     let path = peer.path().to_string();
-    let res = match peer {
-        Peer::Char(c) => connect_serial(c, broadcast_rx, mspc_tx).await,
-        Peer::Sock(s) => connect_sock(s, broadcast_rx, mspc_tx).await,
-        Peer::SockListen(s) => connect_sock_listen(s, broadcast_rx, mspc_tx).await,
-    };
+    let mut backoff = Backoff::new(peer.link());
+    // bound once, so that the socket file stays put between connections
+    let unix_socket = OnceCell::new();
+    loop {
+        state.set_link(&path, LinkState::Connecting);
+        let res = match peer.clone() {
+            Peer::Char(c) => {
+                connect_serial(c, &mut packet_rx, mspc_tx.clone(), state.clone()).await
+            }
+            Peer::Sock(s) => connect_sock(s, &mut packet_rx, mspc_tx.clone(), state.clone()).await,
+            Peer::SockListen(s) => {
+                connect_sock_listen(
+                    s,
+                    &mut packet_rx,
+                    mspc_tx.clone(),
+                    state.clone(),
+                    &listeners,
+                )
+                .await
+            }
+            Peer::Tls(t) => connect_tls(t, &mut packet_rx, mspc_tx.clone(), state.clone()).await,
+            Peer::TlsListen(t) => {
+                connect_tls_listen(
+                    t,
+                    &mut packet_rx,
+                    mspc_tx.clone(),
+                    state.clone(),
+                    &listeners,
+                )
+                .await
+            }
+            Peer::Ws(w) => connect_ws(w, &mut packet_rx, mspc_tx.clone(), state.clone()).await,
+            Peer::WsListen(w) => {
+                connect_ws_listen(
+                    w,
+                    &mut packet_rx,
+                    mspc_tx.clone(),
+                    state.clone(),
+                    &listeners,
+                )
+                .await
+            }
+            Peer::Udp(u) => connect_udp(u, &mut packet_rx, mspc_tx.clone(), state.clone()).await,
+            Peer::Unix(u) => connect_unix(u, &mut packet_rx, mspc_tx.clone(), state.clone()).await,
+            Peer::UnixListen(u) => {
+                connect_unix_listen(
+                    u,
+                    &mut packet_rx,
+                    mspc_tx.clone(),
+                    state.clone(),
+                    &unix_socket,
+                )
+                .await
+            }
+            Peer::Stdio(s) => {
+                connect_stdio(s, &mut packet_rx, mspc_tx.clone(), state.clone()).await
+            }
+            Peer::Exec(e) => connect_exec(e, &mut packet_rx, mspc_tx.clone(), state.clone()).await,
+        };
 
-    match res {
-        Ok(_) => info!("Connection to {} closed successfully.", path),
-        Err(e) => error!("[{}] Error: {}", path, e),
+        match res {
+            Ok(_) => info!("Connection to {} closed successfully.", path),
+            Err(e) => error!("[{}] Error: {}", path, e),
+        }
+        let lasted = state.link_age();
+        if state.set_link(&path, LinkState::Down) == LinkState::Up {
+            PeerStats::bump(&state.stats.disconnections);
+            info!("[{}] {}", path, state.stats);
+            backoff.connection_lasted(lasted);
+        }
+
+        if let Peer::Stdio(_) = peer {
+            return;
+        }
+
+        if !peer.link().queue_while_down() {
+            while packet_rx.try_recv().is_ok() {
+                PeerStats::bump(&state.stats.dropped_packets);
+            }
+        }
+        let delay = backoff.next_delay();
+        info!("[{}] Reconnecting in {:.1?}.", path, delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExecPeerSection;
+
+    #[tokio::test]
+    async fn exiting_command_backs_off() {
+        let log = std::env::temp_dir().join(format!("ip2char-exec-{}", std::process::id()));
+        let section = format!(
+            "command = \"date +%s.%N >> {}\"\nallowedips = []\nreconnect-delay = 0.1",
+            log.display()
+        );
+        let peer = Peer::Exec(toml::from_str::<ExecPeerSection>(&section).unwrap());
+        let state = Arc::new(PeerState::new(Crypto::None, None));
+        let listeners = Arc::new(Listeners::new(&[], &[]));
+        let (_packet_tx, packet_rx) = mpsc::channel(1);
+        let (mpsc_tx, _mpsc_rx) = mpsc::channel(1);
+        let run = connect_to_peer(peer, packet_rx, mpsc_tx, state, listeners);
+        let _ = tokio::time::timeout(Duration::from_secs(3), run).await;
+
+        let starts: Vec<f64> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| line.parse().unwrap())
+            .collect();
+        let _ = std::fs::remove_file(&log);
+        // 0.1, 0.2, 0.4, 0.8 and 1.6s at most, give or take half of each
+        assert!((4..=8).contains(&starts.len()), "{:?}", starts);
+        let gaps: Vec<f64> = starts.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(gaps[gaps.len() - 1] > 4.0 * gaps[0], "{:?}", gaps);
     }
 }
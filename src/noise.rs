@@ -0,0 +1,362 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use snow::{Builder, HandshakeState};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::crypto::{CryptoError, Opener, Sealer};
+use crate::types::Key;
+
+const PATTERN: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+const PATTERN_PSK: &str = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+
+/// Session keys are renegotiated this often.
+const REKEY_AFTER: Duration = Duration::from_secs(120);
+/// Unanswered handshakes are retried after this long.
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+const MAX_MESSAGE_SIZE: usize = 256;
+const TIMESTAMP_SIZE: usize = 8;
+/// An ephemeral key and the tag of an empty payload, initiations are longer.
+const RESPONSE_SIZE: usize = 48;
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+pub fn public_key(private_key: &Key) -> Key {
+    Key(PublicKey::from(&StaticSecret::from(private_key.0)).to_bytes())
+}
+
+fn builder<'a>(private_key: &'a Key, psk: Option<&'a Key>) -> Builder<'a> {
+    let pattern = if psk.is_some() { PATTERN_PSK } else { PATTERN };
+    let mut builder = Builder::new(pattern.parse().expect("valid noise pattern"))
+        .local_private_key(&private_key.0);
+    if let Some(psk) = psk {
+        builder = builder.psk(2, &psk.0);
+    }
+    builder
+}
+
+/// Noise IK handshakes with a peer, and the session keys derived from them.
+///
+/// Unless one of them only answers, both sides start a new handshake every [`REKEY_AFTER`], so that
+/// every session is encrypted with keys derived from fresh ephemeral keys. When handshakes
+/// cross, the one from the side with the lowest public key goes on.
+pub struct Noise {
+    private_key: Key,
+    remote_public: Key,
+    psk: Option<Key>,
+    initiator: bool,
+    /// whether our handshakes win when they cross the peer's
+    lowest: bool,
+    /// handshake waiting for a response, with its key id
+    pending: Option<(u8, HandshakeState, Instant)>,
+    /// even on the side with the lowest key, odd on the other, so that the sessions either side
+    /// starts never share an id
+    next_key_id: u8,
+    /// newest handshake timestamp from the initiator, older ones are replays
+    last_timestamp: u64,
+    sealer: Option<(u8, Sealer)>,
+    /// responder session, used once the handshake response is sent
+    next_sealer: Option<(u8, Sealer)>,
+    /// current and previous session, frames from both may be in flight
+    openers: Vec<(u8, Opener)>,
+    established: Option<Instant>,
+}
+
+impl Noise {
+    pub fn new(
+        private_key: Key,
+        remote_public: Key,
+        psk: Option<Key>,
+        initiator: bool,
+    ) -> anyhow::Result<Self> {
+        let local_public = public_key(&private_key);
+        if local_public == remote_public {
+            bail!("peer publickey is the same as the interface one");
+        }
+        let lowest = local_public.0 < remote_public.0;
+        Ok(Self {
+            private_key,
+            remote_public,
+            psk,
+            initiator,
+            lowest,
+            pending: None,
+            next_key_id: if lowest { 0 } else { 1 },
+            last_timestamp: 0,
+            sealer: None,
+            next_sealer: None,
+            openers: Vec::with_capacity(2),
+            established: None,
+        })
+    }
+
+    fn builder(&self) -> Builder<'_> {
+        builder(&self.private_key, self.psk.as_ref())
+    }
+
+    /// Drops all sessions, so that a new connection starts with a new handshake.
+    pub fn reset(&mut self) {
+        self.pending = None;
+        self.sealer = None;
+        self.next_sealer = None;
+        self.openers.clear();
+        self.established = None;
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.sealer.is_some()
+    }
+
+    /// Starts a new handshake if one is due.
+    ///
+    /// Returns the key id of the new session, with the first message in `out`.
+    pub fn initiate(&mut self, out: &mut Vec<u8>) -> Result<Option<u8>, CryptoError> {
+        if !self.initiator {
+            return Ok(None);
+        }
+        let due = match (&self.pending, self.established) {
+            (Some((_, _, started)), _) => started.elapsed() >= RETRY_AFTER,
+            (None, Some(established)) => established.elapsed() >= REKEY_AFTER,
+            (None, None) => true,
+        };
+        if !due {
+            return Ok(None);
+        }
+
+        let mut handshake = self
+            .builder()
+            .remote_public_key(&self.remote_public.0)
+            .build_initiator()?;
+        out.resize(MAX_MESSAGE_SIZE, 0);
+        let len = handshake.write_message(&timestamp().to_le_bytes(), out)?;
+        out.truncate(len);
+
+        let key_id = self.next_key_id;
+        self.next_key_id = key_id.wrapping_add(2);
+        self.pending = Some((key_id, handshake, Instant::now()));
+        Ok(Some(key_id))
+    }
+
+    /// Handles a handshake message from the peer.
+    ///
+    /// Returns `true` if `reply` has to be sent back.
+    pub fn handle(
+        &mut self,
+        key_id: u8,
+        message: &[u8],
+        reply: &mut Vec<u8>,
+    ) -> Result<bool, CryptoError> {
+        let mut payload = [0u8; MAX_MESSAGE_SIZE];
+        if message.len() == RESPONSE_SIZE {
+            let mut handshake = match self.pending.take() {
+                Some((id, handshake, _)) if id == key_id => handshake,
+                pending => {
+                    self.pending = pending;
+                    return Err(CryptoError::UnexpectedHandshake);
+                }
+            };
+            handshake.read_message(message, &mut payload)?;
+
+            let (initiator_key, responder_key) = handshake.dangerously_get_raw_split();
+            self.add_opener(key_id, Opener::new(&Key(responder_key)));
+            self.sealer = Some((key_id, Sealer::new(&Key(initiator_key))));
+            self.established = Some(Instant::now());
+            Ok(false)
+        } else {
+            if self.pending.is_some() && self.lowest {
+                return Err(CryptoError::Crossed);
+            }
+            let (mut handshake, timestamp) = self.read_initiation(message)?;
+            self.last_timestamp = timestamp;
+            // the peer answers neither ours, nor another one before this one is through
+            self.pending = None;
+            self.established = Some(Instant::now());
+
+            reply.resize(MAX_MESSAGE_SIZE, 0);
+            let len = handshake.write_message(&[], reply)?;
+            reply.truncate(len);
+
+            let (initiator_key, responder_key) = handshake.dangerously_get_raw_split();
+            self.add_opener(key_id, Opener::new(&Key(initiator_key)));
+            self.next_sealer = Some((key_id, Sealer::new(&Key(responder_key))));
+            Ok(true)
+        }
+    }
+
+    /// Checks that a handshake was started by the peer, and isn't a replay, without answering it.
+    pub fn check_initiation(&self, message: &[u8]) -> Result<(), CryptoError> {
+        self.read_initiation(message).map(|_| ())
+    }
+
+    /// Reads the first message of a handshake from the peer, returns it along with its timestamp.
+    fn read_initiation(&self, message: &[u8]) -> Result<(HandshakeState, u64), CryptoError> {
+        let mut payload = [0u8; MAX_MESSAGE_SIZE];
+        let mut handshake = self.builder().build_responder()?;
+        let len = handshake.read_message(message, &mut payload)?;
+        if handshake.get_remote_static() != Some(&self.remote_public.0[..]) {
+            return Err(CryptoError::UnknownPeer);
+        }
+        if len != TIMESTAMP_SIZE {
+            return Err(CryptoError::TooShort);
+        }
+        let timestamp = u64::from_le_bytes(payload[..TIMESTAMP_SIZE].try_into().unwrap());
+        if timestamp <= self.last_timestamp {
+            return Err(CryptoError::Replay);
+        }
+        Ok((handshake, timestamp))
+    }
+
+    /// Switches to the session of the last handshake response, once it's been sent.
+    pub fn promote(&mut self) -> Option<u8> {
+        let (key_id, sealer) = self.next_sealer.take()?;
+        self.sealer = Some((key_id, sealer));
+        self.established = Some(Instant::now());
+        Some(key_id)
+    }
+
+    fn add_opener(&mut self, key_id: u8, opener: Opener) {
+        self.openers.insert(0, (key_id, opener));
+        self.openers.truncate(2);
+    }
+
+    pub fn sealer(&mut self) -> Option<(u8, &mut Sealer)> {
+        self.sealer.as_mut().map(|(id, sealer)| (*id, sealer))
+    }
+
+    pub fn opener(&mut self, key_id: u8) -> Option<&mut Opener> {
+        self.openers
+            .iter_mut()
+            .find(|(id, _)| *id == key_id)
+            .map(|(_, opener)| opener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Header;
+
+    /// Two ends of a link, the one with the lowest public key first.
+    fn pair(a_initiates: bool, b_initiates: bool) -> (Noise, Noise) {
+        let mut keys = [Key([1; 32]), Key([2; 32])];
+        keys.sort_by_key(|key| public_key(key).0);
+        let [a_key, b_key] = keys;
+        let a = Noise::new(a_key, public_key(&b_key), None, a_initiates).unwrap();
+        let b = Noise::new(b_key, public_key(&a_key), None, b_initiates).unwrap();
+        (a, b)
+    }
+
+    fn ping(from: &mut Noise, to: &mut Noise) {
+        let mut header = Header::default();
+        let (mut sealed, mut opened) = (Vec::new(), Vec::new());
+        let (key_id, sealer) = from.sealer().unwrap();
+        sealer.seal(&mut header, b"ping", &mut sealed).unwrap();
+        let opener = to.opener(key_id).unwrap();
+        opener.open(&header, &sealed, &mut opened).unwrap();
+        assert_eq!(opened, b"ping");
+    }
+
+    /// Checks that both ends can talk with what they have.
+    fn check_session(a: &mut Noise, b: &mut Noise) {
+        ping(a, b);
+        ping(b, a);
+    }
+
+    #[test]
+    fn handshake() {
+        let (mut a, mut b) = pair(true, false);
+        let (mut initiation, mut reply) = (Vec::new(), Vec::new());
+        let id = a.initiate(&mut initiation).unwrap().unwrap();
+        assert_ne!(initiation.len(), RESPONSE_SIZE);
+        assert!(b.initiate(&mut Vec::new()).unwrap().is_none());
+
+        // listeners look at it before handing the connection over
+        b.check_initiation(&initiation).unwrap();
+        assert!(b.handle(id, &initiation, &mut reply).unwrap());
+        assert_eq!(reply.len(), RESPONSE_SIZE);
+        assert_eq!(b.promote(), Some(id));
+        assert!(!a.handle(id, &reply, &mut Vec::new()).unwrap());
+        check_session(&mut a, &mut b);
+
+        assert!(matches!(
+            b.handle(id, &initiation, &mut reply),
+            Err(CryptoError::Replay)
+        ));
+        assert!(matches!(
+            b.check_initiation(&initiation),
+            Err(CryptoError::Replay)
+        ));
+    }
+
+    #[test]
+    fn crossed_handshakes() {
+        let (mut a, mut b) = pair(true, true);
+        let (mut from_a, mut from_b, mut reply) = (Vec::new(), Vec::new(), Vec::new());
+        let a_id = a.initiate(&mut from_a).unwrap().unwrap();
+        let b_id = b.initiate(&mut from_b).unwrap().unwrap();
+
+        // the lowest key keeps its own
+        assert!(matches!(
+            a.handle(b_id, &from_b, &mut reply),
+            Err(CryptoError::Crossed)
+        ));
+        assert!(b.handle(a_id, &from_a, &mut reply).unwrap());
+        b.promote();
+        assert!(!a.handle(a_id, &reply, &mut Vec::new()).unwrap());
+        check_session(&mut a, &mut b);
+
+        // and b doesn't start another one right away
+        assert!(b.initiate(&mut from_b).unwrap().is_none());
+    }
+
+    #[test]
+    fn key_ids_dont_clash() {
+        let (mut a, mut b) = pair(true, true);
+        let (mut from_a, mut from_b, mut reply) = (Vec::new(), Vec::new(), Vec::new());
+        let a_id = a.initiate(&mut from_a).unwrap().unwrap();
+        assert!(b.handle(a_id, &from_a, &mut reply).unwrap());
+        b.promote();
+        assert!(!a.handle(a_id, &reply, &mut Vec::new()).unwrap());
+
+        // a frame of that session still on its way when b starts the next one
+        let (mut header, mut sealed, mut opened) = (Header::default(), Vec::new(), Vec::new());
+        a.sealer()
+            .unwrap()
+            .1
+            .seal(&mut header, b"late", &mut sealed)
+            .unwrap();
+
+        b.established = None;
+        let b_id = b.initiate(&mut from_b).unwrap().unwrap();
+        assert_ne!(a_id, b_id);
+        assert!(a.handle(b_id, &from_b, &mut reply).unwrap());
+        a.promote();
+        assert!(!b.handle(b_id, &reply, &mut Vec::new()).unwrap());
+        check_session(&mut a, &mut b);
+
+        b.opener(a_id)
+            .unwrap()
+            .open(&header, &sealed, &mut opened)
+            .unwrap();
+        assert_eq!(opened, b"late");
+    }
+
+    #[test]
+    fn only_one_initiates() {
+        // b would have to win a crossing, but a only answers
+        let (mut a, mut b) = pair(false, true);
+        let (mut initiation, mut reply) = (Vec::new(), Vec::new());
+        assert!(a.initiate(&mut Vec::new()).unwrap().is_none());
+        let id = b.initiate(&mut initiation).unwrap().unwrap();
+        assert!(a.handle(id, &initiation, &mut reply).unwrap());
+        a.promote();
+        assert!(!b.handle(id, &reply, &mut Vec::new()).unwrap());
+        check_session(&mut a, &mut b);
+    }
+}
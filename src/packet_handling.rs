@@ -1,27 +1,57 @@
+use std::net::{IpAddr, Ipv6Addr};
+
 use bytes::Bytes;
-use packet::ip::v4::Packet;
 use packet::ip::{self};
-use tokio::sync::broadcast;
 use tracing::{trace, warn};
 use tun::TunPacket;
 
+use crate::filter::Filter;
+use crate::routing::Router;
+
 pub fn handle_packet_from_kernel(
     data: Bytes,
-    tx: &broadcast::Sender<Packet<Bytes>>,
+    filter: &Filter,
+    router: &Router,
 ) -> anyhow::Result<()> {
-    match ip::Packet::new(data) {
+    match ip::Packet::new(&data[..]) {
         Ok(ip::Packet::V4(pkt)) => {
-            tx.send(pkt)?;
-        }
-        Ok(ip::Packet::V6(_pkt)) => {
-            //tracing::trace!("V6 packet, cant do anything about it for now");
+            let destination = IpAddr::V4(pkt.destination());
+            if filter.allow(destination) {
+                router.dispatch(destination, data);
+            }
         }
+        Ok(ip::Packet::V6(_)) => match ipv6_address(&data, IPV6_DESTINATION) {
+            Some(destination) => {
+                let destination = IpAddr::V6(destination);
+                if filter.allow(destination) {
+                    router.dispatch(destination, data);
+                }
+            }
+            None => warn!("Received an invalid packet: truncated IPv6 header"),
+        },
         Err(err) => warn!("Received an invalid packet: {:?}", err),
     }
 
     Ok(())
 }
 
+const IPV6_SOURCE: usize = 8;
+const IPV6_DESTINATION: usize = 24;
+
+/// The `packet` crate can't read addresses from IPv6 packets yet.
+fn ipv6_address(data: &[u8], offset: usize) -> Option<Ipv6Addr> {
+    let bytes: [u8; 16] = data.get(offset..offset + 16)?.try_into().ok()?;
+    Some(Ipv6Addr::from(bytes))
+}
+
+pub fn packet_source(data: &[u8]) -> Option<IpAddr> {
+    match ip::Packet::new(data) {
+        Ok(ip::Packet::V4(pkt)) => Some(IpAddr::V4(pkt.source())),
+        Ok(ip::Packet::V6(_)) => ipv6_address(data, IPV6_SOURCE).map(IpAddr::V6),
+        Err(_) => None,
+    }
+}
+
 pub fn prep_packet_for_kernel(packet: Bytes) -> anyhow::Result<TunPacket> {
     trace!("Sending packet to kernel");
     // ugh very bad for performance
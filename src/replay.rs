@@ -0,0 +1,177 @@
+use crate::crypto::CryptoError;
+
+const WORDS: usize = 32;
+const BITS: u64 = 64;
+/// How far behind the newest counter a frame can be and still be accepted.
+const WINDOW_SIZE: u64 = (WORDS as u64 - 1) * BITS;
+
+/// Sliding window of the counters seen recently, to reject replayed frames (RFC 6479).
+pub struct ReplayWindow {
+    top: u64,
+    bitmap: Box<[u64; WORDS]>,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            top: 0,
+            bitmap: Box::new([0; WORDS]),
+        }
+    }
+
+    /// Checks that `counter` is neither a duplicate nor too old.
+    pub fn check(&self, counter: u64) -> Result<(), CryptoError> {
+        if counter.saturating_add(WINDOW_SIZE) < self.top {
+            return Err(CryptoError::TooOld(counter));
+        }
+        if counter > self.top {
+            return Ok(());
+        }
+        let word = (counter / BITS) as usize % WORDS;
+        if self.bitmap[word] & (1 << (counter % BITS)) != 0 {
+            return Err(CryptoError::Replayed(counter));
+        }
+        Ok(())
+    }
+
+    /// Marks `counter` as seen, only call this once the frame is authenticated.
+    pub fn update(&mut self, counter: u64) {
+        let index = counter / BITS;
+        if counter > self.top {
+            let top_index = self.top / BITS;
+            let diff = (index - top_index).min(WORDS as u64);
+            for i in 1..=diff {
+                self.bitmap[((top_index + i) % WORDS as u64) as usize] = 0;
+            }
+            self.top = counter;
+        }
+        self.bitmap[(index % WORDS as u64) as usize] |= 1 << (counter % BITS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks and marks a counter, like `Opener` does with an authenticated frame.
+    fn accept(window: &mut ReplayWindow, counter: u64) -> Result<(), CryptoError> {
+        window.check(counter)?;
+        window.update(counter);
+        Ok(())
+    }
+
+    #[test]
+    fn in_order() {
+        let mut window = ReplayWindow::new();
+        for counter in 0..10 * WINDOW_SIZE {
+            assert!(accept(&mut window, counter).is_ok(), "{}", counter);
+        }
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut window = ReplayWindow::new();
+        accept(&mut window, 5).unwrap();
+        assert!(matches!(window.check(5), Err(CryptoError::Replayed(5))));
+        accept(&mut window, 6).unwrap();
+        assert!(matches!(window.check(5), Err(CryptoError::Replayed(5))));
+        assert!(matches!(window.check(6), Err(CryptoError::Replayed(6))));
+    }
+
+    #[test]
+    fn out_of_order_in_window() {
+        let mut window = ReplayWindow::new();
+        let top = 10_000;
+        accept(&mut window, top).unwrap();
+        for counter in (top - WINDOW_SIZE..top).rev() {
+            assert!(accept(&mut window, counter).is_ok(), "{}", counter);
+        }
+        for counter in top - WINDOW_SIZE..=top {
+            assert!(matches!(
+                window.check(counter),
+                Err(CryptoError::Replayed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn too_old() {
+        let mut window = ReplayWindow::new();
+        let top = 10_000;
+        accept(&mut window, top).unwrap();
+        let oldest = top - WINDOW_SIZE;
+        assert!(matches!(
+            window.check(oldest - 1),
+            Err(CryptoError::TooOld(_))
+        ));
+        assert!(matches!(window.check(0), Err(CryptoError::TooOld(0))));
+        assert!(accept(&mut window, oldest).is_ok());
+    }
+
+    #[test]
+    fn large_jump() {
+        let mut window = ReplayWindow::new();
+        for counter in 0..100 {
+            accept(&mut window, counter).unwrap();
+        }
+        let top = 1 << 40;
+        accept(&mut window, top).unwrap();
+        assert!(matches!(window.check(99), Err(CryptoError::TooOld(99))));
+        // nothing in between was seen
+        for counter in top - WINDOW_SIZE..top {
+            assert!(window.check(counter).is_ok(), "{}", counter);
+        }
+        assert!(accept(&mut window, u64::MAX).is_ok());
+        assert!(matches!(
+            window.check(u64::MAX),
+            Err(CryptoError::Replayed(_))
+        ));
+    }
+
+    #[test]
+    fn word_boundary() {
+        let mut window = ReplayWindow::new();
+        accept(&mut window, BITS - 1).unwrap();
+        accept(&mut window, BITS).unwrap();
+        assert!(matches!(
+            window.check(BITS - 1),
+            Err(CryptoError::Replayed(_))
+        ));
+        assert!(matches!(window.check(BITS), Err(CryptoError::Replayed(_))));
+        assert!(window.check(BITS - 2).is_ok());
+        assert!(window.check(BITS + 1).is_ok());
+        // moving into the next word leaves the previous one alone
+        accept(&mut window, 2 * BITS + 3).unwrap();
+        assert!(matches!(
+            window.check(BITS - 1),
+            Err(CryptoError::Replayed(_))
+        ));
+        assert!(window.check(2 * BITS + 2).is_ok());
+    }
+
+    #[test]
+    fn ring_clears_reused_words() {
+        let mut window = ReplayWindow::new();
+        let ring = WORDS as u64 * BITS;
+        accept(&mut window, 5).unwrap();
+        accept(&mut window, 7).unwrap();
+        // lands in the same word of the ring as 5 and 7
+        accept(&mut window, ring + 10).unwrap();
+        assert!(window.check(ring + 5).is_ok());
+        assert!(window.check(ring + 7).is_ok());
+        assert!(matches!(
+            window.check(ring + 10),
+            Err(CryptoError::Replayed(_))
+        ));
+        // while the words in between are still there
+        accept(&mut window, ring - 1).unwrap();
+        assert!(matches!(
+            window.check(ring - 1),
+            Err(CryptoError::Replayed(_))
+        ));
+        // going all the way around clears everything
+        accept(&mut window, 3 * ring).unwrap();
+        assert!(window.check(3 * ring - 1).is_ok());
+        assert!(window.check(3 * ring - WINDOW_SIZE).is_ok());
+    }
+}
@@ -0,0 +1,166 @@
+use std::cmp::Reverse;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use ipnetwork::IpNetwork;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{trace, warn};
+
+use crate::config::Peer;
+use crate::stats::PeerStats;
+use crate::streams::{LinkState, PeerState};
+
+struct Target {
+    path: String,
+    tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+    queue_while_down: bool,
+}
+
+/// Sends each packet to the one peer whose `allowedips` match its
+/// destination most closely, like Wireguard's cryptokey routing.
+#[derive(Default)]
+pub struct Router {
+    /// sorted from the longest prefix to the shortest
+    routes: Vec<(IpNetwork, usize)>,
+    targets: Vec<Target>,
+}
+
+impl Router {
+    pub fn add_peer(&mut self, peer: &Peer, tx: mpsc::Sender<Bytes>, state: Arc<PeerState>) {
+        let index = self.targets.len();
+        for network in peer.allowed_ips() {
+            if let Some((_, other)) = self.routes.iter().find(|(n, _)| n == network) {
+                warn!(
+                    "[{}] {} is already routed to {}, ignoring it.",
+                    peer.path(),
+                    network,
+                    self.targets[*other].path
+                );
+                continue;
+            }
+            self.routes.push((*network, index));
+        }
+        self.routes
+            .sort_by_key(|(network, _)| Reverse(network.prefix()));
+        self.targets.push(Target {
+            path: peer.path().to_string(),
+            tx,
+            state,
+            queue_while_down: peer.link().queue_while_down(),
+        });
+    }
+
+    fn lookup(&self, destination: IpAddr) -> Option<&Target> {
+        self.routes
+            .iter()
+            .find(|(network, _)| network.contains(destination))
+            .map(|(_, index)| &self.targets[*index])
+    }
+
+    /// Queues a packet for the peer it's routed to, dropping it if there's none.
+    pub fn dispatch(&self, destination: IpAddr, packet: Bytes) {
+        let Some(target) = self.lookup(destination) else {
+            trace!("No route to {}", destination);
+            return;
+        };
+        if !target.queue_while_down && target.state.link() != LinkState::Up {
+            let n = PeerStats::bump(&target.state.stats.dropped_packets);
+            trace!(
+                "[{}] Link is down, dropped packet ({} so far)",
+                target.path,
+                n
+            );
+            return;
+        }
+        match target.tx.try_send(packet) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let n = PeerStats::bump(&target.state.stats.dropped_packets);
+                trace!(
+                    "[{}] Queue full, dropped packet ({} so far)",
+                    target.path,
+                    n
+                );
+            }
+            Err(TrySendError::Closed(_)) => {
+                let n = PeerStats::bump(&target.state.stats.dropped_packets);
+                trace!(
+                    "[{}] Peer is gone, dropped packet ({} so far)",
+                    target.path,
+                    n
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crypto;
+
+    fn router(peers: &[(&str, &str)]) -> Router {
+        let mut router = Router::default();
+        for (path, allowedips) in peers {
+            let section = format!("path = '{}'\nallowedips = [{}]", path, allowedips);
+            let peer = Peer::Sock(toml::from_str(&section).unwrap());
+            let state = Arc::new(PeerState::new(Crypto::None, None));
+            router.add_peer(&peer, mpsc::channel(1).0, state);
+        }
+        router
+    }
+
+    fn route(router: &Router, destination: &str) -> Option<String> {
+        let target = router.lookup(destination.parse().unwrap())?;
+        Some(target.path.clone())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        // added from the shortest prefix to the longest, so that order doesn't decide
+        let router = router(&[
+            ("default", "'0.0.0.0/0'"),
+            ("wide", "'10.0.0.0/8'"),
+            ("narrow", "'10.1.0.0/16', '10.1.2.3/32'"),
+            ("narrower", "'10.1.2.0/24'"),
+        ]);
+        assert_eq!(route(&router, "10.1.2.3").as_deref(), Some("narrow"));
+        assert_eq!(route(&router, "10.1.2.4").as_deref(), Some("narrower"));
+        assert_eq!(route(&router, "10.1.3.4").as_deref(), Some("narrow"));
+        assert_eq!(route(&router, "10.2.3.4").as_deref(), Some("wide"));
+        assert_eq!(route(&router, "192.168.1.1").as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn longest_prefix_wins_for_ipv6() {
+        let router = router(&[
+            ("default", "'::/0'"),
+            ("wide", "'fd00::/8'"),
+            ("narrow", "'fd00:1::/32', 'fd00:1:2::3/128'"),
+            ("narrower", "'fd00:1:2::/48'"),
+        ]);
+        assert_eq!(route(&router, "fd00:1:2::3").as_deref(), Some("narrow"));
+        assert_eq!(route(&router, "fd00:1:2::4").as_deref(), Some("narrower"));
+        assert_eq!(route(&router, "fd00:1:3::4").as_deref(), Some("narrow"));
+        assert_eq!(route(&router, "fd00:2::4").as_deref(), Some("wide"));
+        assert_eq!(route(&router, "2001:db8::1").as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn families_stay_apart() {
+        let router = router(&[("v4", "'0.0.0.0/0'"), ("v6", "'::/0'")]);
+        assert_eq!(route(&router, "10.0.0.1").as_deref(), Some("v4"));
+        assert_eq!(route(&router, "::ffff:10.0.0.1").as_deref(), Some("v6"));
+        assert_eq!(route(&router, "fd00::1").as_deref(), Some("v6"));
+    }
+
+    #[test]
+    fn first_peer_keeps_a_network() {
+        let router = router(&[("first", "'10.0.0.0/8'"), ("second", "'10.0.0.0/8'")]);
+        assert_eq!(route(&router, "10.0.0.1").as_deref(), Some("first"));
+        assert_eq!(route(&router, "11.0.0.1"), None);
+    }
+}
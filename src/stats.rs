@@ -0,0 +1,83 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::filter::DropReason;
+
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    pub corrupt_frames: AtomicU64,
+    pub other_version_frames: AtomicU64,
+    pub fec_corrected_frames: AtomicU64,
+    pub fec_uncorrectable_frames: AtomicU64,
+    pub decryption_failures: AtomicU64,
+    pub handshake_failures: AtomicU64,
+    pub replayed_frames: AtomicU64,
+    pub too_old_frames: AtomicU64,
+    pub dropped_packets: AtomicU64,
+    pub spoofed_packets: AtomicU64,
+    pub disconnections: AtomicU64,
+}
+
+impl PeerStats {
+    /// Increments a counter and returns its new value.
+    pub fn bump(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corrupt frames: {}, other version frames: {}, fec corrected frames: {}, \
+             fec uncorrectable frames: {}, decryption failures: {}, handshake failures: {}, \
+             replayed frames: {}, too old frames: {}, dropped packets: {}, spoofed packets: {}, \
+             disconnections: {}",
+            self.corrupt_frames.load(Ordering::Relaxed),
+            self.other_version_frames.load(Ordering::Relaxed),
+            self.fec_corrected_frames.load(Ordering::Relaxed),
+            self.fec_uncorrectable_frames.load(Ordering::Relaxed),
+            self.decryption_failures.load(Ordering::Relaxed),
+            self.handshake_failures.load(Ordering::Relaxed),
+            self.replayed_frames.load(Ordering::Relaxed),
+            self.too_old_frames.load(Ordering::Relaxed),
+            self.dropped_packets.load(Ordering::Relaxed),
+            self.spoofed_packets.load(Ordering::Relaxed),
+            self.disconnections.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Packets dropped by the filter, by reason.
+#[derive(Debug, Default)]
+pub struct FilterStats {
+    pub broadcast: AtomicU64,
+    pub multicast: AtomicU64,
+    pub link_local: AtomicU64,
+    pub ipv6: AtomicU64,
+}
+
+impl FilterStats {
+    /// Increments the counter for `reason` and returns its new value.
+    pub fn bump(&self, reason: DropReason) -> u64 {
+        PeerStats::bump(match reason {
+            DropReason::Broadcast => &self.broadcast,
+            DropReason::Multicast => &self.multicast,
+            DropReason::LinkLocal => &self.link_local,
+            DropReason::Ipv6 => &self.ipv6,
+        })
+    }
+}
+
+impl fmt::Display for FilterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "filtered broadcast: {}, multicast: {}, link-local: {}, IPv6: {}",
+            self.broadcast.load(Ordering::Relaxed),
+            self.multicast.load(Ordering::Relaxed),
+            self.link_local.load(Ordering::Relaxed),
+            self.ipv6.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -1,124 +1,361 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use bytes::Bytes;
-use packet::ip::v4::Packet;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
-use tokio::sync::{broadcast, mpsc};
+use ipnetwork::IpNetwork;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tracing::{info, trace, warn};
 
 use crate::config::Peer;
-use crate::types::{Header, MARKER_SIZE, SYNC_MARKER};
-use crate::{compression, utils, HEADER_SIZE};
+use crate::crypto::{Crypto, CryptoError};
+use crate::fec::Fec;
+use crate::framing::cobs::{self, CobsReader, CobsWriter};
+use crate::framing::hdlc;
+use crate::framing::raw::{RawReader, RawWriter};
+use crate::framing::slip::{SlipReader, SlipWriter};
+use crate::framing::{BadFrame, FrameReader, FrameWriter, MAX_PAYLOAD};
+use crate::packet_handling::packet_source;
+use crate::stats::PeerStats;
+use crate::types::{EncryptionType, FrameKind, Framing, Header, VERSION};
+use crate::{compression, utils};
+
+/// Whether a peer is connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Down,
+    Connecting,
+    Up,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Down => write!(f, "down"),
+            LinkState::Connecting => write!(f, "connecting"),
+            LinkState::Up => write!(f, "up"),
+        }
+    }
+}
+
+/// State of a peer that outlives its connections.
+pub struct PeerState {
+    pub stats: PeerStats,
+    pub crypto: Crypto,
+    /// Our address on the tunnel, for PPP to tell the peer.
+    pub address: Option<Ipv4Addr>,
+    /// with when it got there
+    link: Mutex<(LinkState, Instant)>,
+}
+
+impl PeerState {
+    pub fn new(crypto: Crypto, address: Option<Ipv4Addr>) -> Self {
+        Self {
+            stats: PeerStats::default(),
+            crypto,
+            address,
+            link: Mutex::new((LinkState::Down, Instant::now())),
+        }
+    }
+
+    pub fn link(&self) -> LinkState {
+        utils::lock(&self.link).0
+    }
+
+    /// How long the link has been in its current state.
+    pub fn link_age(&self) -> Duration {
+        utils::lock(&self.link).1.elapsed()
+    }
+
+    /// Changes the link state, logging the transition, and returns the previous one.
+    pub fn set_link(&self, path: &str, link: LinkState) -> LinkState {
+        let mut current = utils::lock(&self.link);
+        let previous = current.0;
+        if previous != link {
+            *current = (link, Instant::now());
+            info!("[{}] Link is {} (was {}).", path, link, previous);
+        }
+        previous
+    }
+
+    /// Counts a dropped frame, only telling the first time that the peer speaks another version.
+    pub fn drop_frame(&self, path: &str, bad: BadFrame) {
+        match bad {
+            BadFrame::Corrupt => {
+                let n = PeerStats::bump(&self.stats.corrupt_frames);
+                warn!("[{}] Dropped corrupt frame ({} so far)", path, n);
+            }
+            BadFrame::OtherVersion(version) => {
+                if PeerStats::bump(&self.stats.other_version_frames) == 1 {
+                    warn!(
+                        "[{}] Peer speaks protocol v{}, not v{}, dropping its frames.",
+                        path, version, VERSION
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Seconds between COBS offers, until the peer answers.
+const COBS_OFFER_TICKS: u32 = 5;
 
-async fn read_from_stream<R>(
-    mut stream: ReadHalf<R>,
+/// Messages from the reading half of a connection to the writing half.
+enum Control {
+    /// A handshake reply to send back.
+    Reply(Header, Vec<u8>),
+    /// A new session is ready to be used.
+    Ready,
+    /// The peer offered COBS framing, and tells whether it heard our offer.
+    CobsOffer { heard: bool },
+}
+
+/// Handles the intact frames coming from a peer, whatever the transport.
+struct Inbound {
     mpsc_tx: mpsc::Sender<Bytes>,
+    ctrl_tx: mpsc::Sender<Control>,
     peer: Peer,
+    state: Arc<PeerState>,
+    opened: Vec<u8>,
+    reply: Vec<u8>,
+}
+
+impl Inbound {
+    /// Lets `reader` accept the frame as soon as it proves genuine, before anything gets sent
+    /// back, so that replies go to wherever it came from.
+    async fn handle<R>(&mut self, reader: &mut R, h: &Header, payload: &[u8]) -> anyhow::Result<()>
+    where
+        R: FrameReader,
+    {
+        let peer = &self.peer;
+        let stats = &self.state.stats;
+        if h.kind == FrameKind::Keepalive {
+            // a sealed one starts the connection, taking it in stops it from being replayed to
+            // a listener
+            if h.encryption == EncryptionType::ChaCha20Poly1305 {
+                if self.state.crypto.open(h, payload, &mut self.opened).is_ok() {
+                    reader.accept();
+                }
+                return Ok(());
+            }
+            if peer.link().framing() == Framing::Cobs {
+                if let Some(heard) = cobs::parse_offer(payload) {
+                    self.ctrl_tx.send(Control::CobsOffer { heard }).await?;
+                }
+            }
+            // others can come from anyone, so they don't prove anything
+            return Ok(());
+        }
+        if h.kind == FrameKind::Handshake {
+            match self.state.crypto.handshake(h, payload, &mut self.reply) {
+                Ok(Some(header)) => {
+                    reader.accept();
+                    self.ctrl_tx
+                        .send(Control::Reply(header, self.reply.clone()))
+                        .await?
+                }
+                Ok(None) => {
+                    reader.accept();
+                    info!("[{}] Established session {}.", peer.path(), h.key_id);
+                    self.ctrl_tx.send(Control::Ready).await?
+                }
+                Err(CryptoError::Crossed) => {
+                    trace!("[{}] Handshakes crossed, going on with ours.", peer.path());
+                }
+                Err(e) => {
+                    let n = PeerStats::bump(&stats.handshake_failures);
+                    warn!("[{}] Dropped handshake: {} ({} so far)", peer.path(), e, n);
+                }
+            }
+            return Ok(());
+        }
+        if let Err(e) = self.state.crypto.open(h, payload, &mut self.opened) {
+            let counter = match e {
+                CryptoError::Replayed(_) | CryptoError::Reflected(_) => &stats.replayed_frames,
+                CryptoError::TooOld(_) => &stats.too_old_frames,
+                _ => &stats.decryption_failures,
+            };
+            let n = PeerStats::bump(counter);
+            warn!("[{}] Dropped frame: {} ({} so far)", peer.path(), e, n);
+            return Ok(());
+        }
+        reader.accept();
+        let packet = compression::decompress_into_bytes(&self.opened, h.compression).await?;
+        // reverse path check, the peer can only send from its allowedips
+        if peer.source_check() {
+            match packet_source(&packet) {
+                Some(source) if utils::check_peer_allowed_ip(&source, peer) => {}
+                source => {
+                    let n = PeerStats::bump(&stats.spoofed_packets);
+                    warn!(
+                        "[{}] Dropped packet from disallowed source {:?} ({} so far)",
+                        peer.path(),
+                        source,
+                        n
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        self.mpsc_tx.send(packet).await?;
+        Ok(())
+    }
+}
+
+async fn read_frames<R>(mut reader: R, mut inbound: Inbound) -> anyhow::Result<()>
+where
+    R: FrameReader,
+{
+    let mut payload = Vec::with_capacity(MAX_PAYLOAD);
+    loop {
+        let header = reader.read_frame(&mut payload).await?;
+        inbound.handle(&mut reader, &header, &payload).await?;
+    }
+}
+
+async fn write_frames<W>(
+    mut writer: W,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mut ctrl_rx: mpsc::Receiver<Control>,
+    peer: Peer,
+    state: Arc<PeerState>,
 ) -> anyhow::Result<()>
 where
-    R: AsyncRead + Unpin,
+    W: FrameWriter,
 {
     let mut buf = [0u8; 1600];
-    let mut header_buf = [0u8; HEADER_SIZE];
-    let mut header: Option<Header> = None;
-    let mut desynced = false;
+    let mut sealed = Vec::new();
+    let mut handshake = Vec::new();
+    // checks whether a handshake is due
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut ticks: u32 = 0;
+    // whether the peer offered COBS, and whether it heard our offer
+    let (mut heard, mut confirmed) = (false, false);
+
+    // tells listeners who connected before any packet goes through, noise has its handshake
+    if state.crypto.is_ready() {
+        let mut hello = Header {
+            kind: FrameKind::Keepalive,
+            ..Default::default()
+        };
+        state.crypto.seal(&mut hello, &[], &mut sealed)?;
+        writer.write_frame(hello, &sealed).await?;
+    }
 
     loop {
-        if desynced {
-            // packet is malformed, we need to resync to the next packet with marker
-            let mut skip: usize = 0;
-            loop {
-                header_buf.copy_within(1.., 0);
-                header_buf[header_buf.len() - 1] = stream.read_u8().await?;
-                skip += 1;
-
-                if header_buf[..MARKER_SIZE] == SYNC_MARKER {
-                    // found it
-                    // read header
-                    match Header::from_slice(&header_buf) {
-                        Ok(e) => {
-                            header = Some(e);
-                            desynced = false;
-                            info!("[{}] Fixed desync, skipped {} bytes.", peer.path(), skip);
-                            break;
+        // hold packets back until there are keys to encrypt them with
+        let ready = state.crypto.is_ready();
+        let packet = select! {
+            Some(control) = ctrl_rx.recv() => {
+                match control {
+                    Control::Reply(header, reply) => {
+                        writer.write_frame(header, &reply).await?;
+                        if let Some(key_id) = state.crypto.promote() {
+                            info!("[{}] Established session {}.", peer.path(), key_id);
+                        }
+                    }
+                    Control::Ready => {}
+                    Control::CobsOffer { heard: peer_heard } => {
+                        if !heard {
+                            info!("[{}] Switching to COBS framing.", peer.path());
+                            writer.use_cobs();
+                            heard = true;
                         }
-                        Err(e) => {
-                            warn!("[{}] Found bad marker: {}", peer.path(), e);
+                        confirmed |= peer_heard;
+                        // it doesn't know yet that it can switch too
+                        if !peer_heard {
+                            write_cobs_offer(&mut writer, true).await?;
                         }
                     }
                 }
-            }
-        }
-        if let Some(h) = header {
-            if h.packet_length > 1500 {
-                warn!("[{}] Stream desync", peer.path());
-                desynced = true;
                 continue;
             }
-            let b = &mut buf[0..h.packet_length as usize];
-            stream.read_exact(b).await?;
-            header = None;
-            mpsc_tx.send(compression::decompress_into_bytes(b, h.compression).await?).await?;
-        } else {
-            stream.read_exact(&mut header_buf).await?;
-            match Header::from_slice(&header_buf) {
-                Ok(e) => header = Some(e),
-                Err(e) => {
-                    warn!("[{}] Stream desync: {}", peer.path(), e);
-                    desynced = true;
+            _ = ticker.tick() => {
+                if let Some(header) = state.crypto.initiate(&mut handshake)? {
+                    trace!("[{}] Starting handshake {}", peer.path(), header.key_id);
+                    writer.write_frame(header, &handshake).await?;
                 }
+                // after the handshake, which shared listeners need first
+                let offering = peer.link().framing() == Framing::Cobs && !(heard && confirmed);
+                if offering && ticks.is_multiple_of(COBS_OFFER_TICKS) {
+                    write_cobs_offer(&mut writer, heard).await?;
+                }
+                ticks = ticks.wrapping_add(1);
+                continue;
             }
-        }
+            packet = packet_rx.recv(), if ready => match packet {
+                Some(p) => p,
+                None => return Ok(()),
+            },
+        };
+        trace!("Sending packet from kernel");
+        // generate a header
+        let mut a = Header::default();
+
+        let compressed_size =
+            compression::compress_into_buf(&packet, &mut buf, peer.compression()).await?;
+
+        a.compression = peer.compression();
+        state
+            .crypto
+            .seal(&mut a, &buf[..compressed_size], &mut sealed)?;
+        writer.write_frame(a, &sealed).await?;
     }
 }
 
-async fn write_to_stream<W>(
-    mut stream: WriteHalf<W>,
-    mut broadcast_rx: broadcast::Receiver<Packet<Bytes>>,
+async fn write_cobs_offer<W: FrameWriter>(writer: &mut W, heard: bool) -> anyhow::Result<()> {
+    let header = Header {
+        kind: FrameKind::Keepalive,
+        ..Default::default()
+    };
+    writer.write_frame(header, &cobs::offer(heard)).await
+}
+
+/// Exchanges frames with a peer until the connection breaks.
+pub async fn handle_frames<R, W>(
+    reader: R,
+    writer: W,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
     peer: Peer,
+    state: Arc<PeerState>,
 ) -> anyhow::Result<()>
 where
-    W: AsyncWrite + Unpin,
+    R: FrameReader + Send + 'static,
+    W: FrameWriter,
 {
-    let mut buf = [0u8; 1600];
+    state.crypto.reset();
+    state.set_link(&peer.path(), LinkState::Up);
+    let (ctrl_tx, ctrl_rx) = mpsc::channel(8);
 
-    loop {
-        let packet: Packet<Bytes>;
-        loop {
-            match broadcast_rx.recv().await {
-                Ok(p) => {
-                    packet = p;
-                    break;
-                }
-                Err(broadcast::error::RecvError::Lagged(n)) => {
-                    warn!("[{}] Lost {} packets!", peer.path(), n);
-                    continue;
-                }
-                Err(e) => return Err(e.into()),
-            };
-        }
-        // check if packet is for us
-        if utils::check_peer_allowed_ip(&packet.destination(), &peer) {
-            trace!("Sending packet from kernel");
-            // generate a header
-            let mut a = Header::default();
-
-            let compressed_size =
-                compression::compress_into_buf(packet.as_ref(), &mut buf, peer.compression())
-                    .await?;
-
-            a.packet_length = compressed_size as u16;
-            a.compression = peer.compression();
-            let header_buf: [u8; HEADER_SIZE] = a.into();
-            stream.write_all(&header_buf).await?;
-            stream.write_all(&buf[..compressed_size]).await?;
-        }
+    let inbound = Inbound {
+        mpsc_tx,
+        ctrl_tx,
+        peer: peer.clone(),
+        state: state.clone(),
+        opened: Vec::new(),
+        reply: Vec::new(),
+    };
+    let mut read_task = AbortOnDrop(tokio::task::spawn(read_frames(reader, inbound)));
+    let write = write_frames(writer, packet_rx, ctrl_rx, peer, state);
+
+    // the connection is over as soon as either half is
+    select! {
+        res = write => res,
+        res = &mut read_task.0 => res?,
     }
 }
 
 pub async fn handle_stream<S>(
     stream: S,
-    broadcast_rx: broadcast::Receiver<Packet<Bytes>>,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
     mpsc_tx: mpsc::Sender<Bytes>,
     peer: Peer,
+    state: Arc<PeerState>,
 ) -> anyhow::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -129,8 +366,132 @@ where
     // EDIT: it's currently breaking everything so i disabled it for now
     //let buf_stream = tokio::io::BufStream::new(stream);
     let (read, write) = tokio::io::split(stream);
-    let read_task = tokio::task::spawn(read_from_stream(read, mpsc_tx, peer.clone()));
-    write_to_stream(write, broadcast_rx, peer).await?;
+    match peer.link().framing() {
+        Framing::Raw => {
+            let reader = RawReader::new(
+                read,
+                peer.fec().map(Fec::new).transpose()?,
+                peer.path().to_string(),
+                state.clone(),
+            );
+            let writer = RawWriter::new(write, peer.fec().map(Fec::new).transpose()?);
+            handle_frames(reader, writer, packet_rx, mpsc_tx, peer, state).await
+        }
+        Framing::Cobs => {
+            let reader = CobsReader::new(read, peer.path().to_string(), state.clone());
+            let writer = CobsWriter::new(write);
+            handle_frames(reader, writer, packet_rx, mpsc_tx, peer, state).await
+        }
+        Framing::Hdlc => {
+            // the address the peer gets if it asks for one
+            let remote = peer.allowed_ips().iter().find_map(|net| match net {
+                IpNetwork::V4(net) if net.prefix() == 32 => Some(net.ip()),
+                _ => None,
+            });
+            let (reader, writer) = hdlc::new(
+                read,
+                write,
+                state.address,
+                remote,
+                peer.link().fcs() == 32,
+                peer.path().to_string(),
+                state.clone(),
+            );
+            handle_frames(reader, writer, packet_rx, mpsc_tx, peer, state).await
+        }
+        framing @ (Framing::Slip | Framing::Cslip) => {
+            let cslip = framing == Framing::Cslip;
+            let reader = SlipReader::new(read, cslip, peer.path().to_string(), state.clone());
+            let writer = SlipWriter::new(write, cslip);
+            handle_frames(reader, writer, packet_rx, mpsc_tx, peer, state).await
+        }
+    }
+}
+
+/// Stops the reading half of a connection when the connection gets dropped,
+/// so that it doesn't keep the stream open.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::framing::encode_frame;
 
-    read_task.await?
+    /// Feeds raw frames to a peer, returning the packets it let through and its state.
+    async fn read_all(peer: Peer, bytes: &[u8]) -> (Vec<Bytes>, Arc<PeerState>) {
+        let state = Arc::new(PeerState::new(Crypto::None, None));
+        let (mut near, far) = tokio::io::duplex(4096);
+        let (read, _write) = tokio::io::split(far);
+        let reader = RawReader::new(read, None, peer.path().to_string(), state.clone());
+        let (tx, mut rx) = mpsc::channel(16);
+        let (ctrl_tx, _ctrl_rx) = mpsc::channel(8);
+        let inbound = Inbound {
+            mpsc_tx: tx,
+            ctrl_tx,
+            peer,
+            state: state.clone(),
+            opened: Vec::new(),
+            reply: Vec::new(),
+        };
+        let task = tokio::spawn(read_frames(reader, inbound));
+
+        near.write_all(bytes).await.unwrap();
+        drop(near);
+        assert!(task.await.unwrap().is_err());
+
+        let mut packets = vec![];
+        while let Ok(packet) = rx.try_recv() {
+            packets.push(packet);
+        }
+        (packets, state)
+    }
+
+    fn ipv4_packet(source: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0];
+        packet.extend_from_slice(&source);
+        packet.extend_from_slice(&[10, 1, 0, 1]);
+        packet
+    }
+
+    fn ipv6_packet(source: &str) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+        packet.extend_from_slice(&source.parse::<std::net::Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&"fd00::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        packet
+    }
+
+    #[tokio::test]
+    async fn spoofed_source_is_dropped() {
+        let packets = [
+            ipv4_packet([10, 1, 0, 2]),
+            ipv4_packet([10, 1, 0, 3]),
+            ipv6_packet("fd00::2"),
+            ipv6_packet("fd00::3"),
+            b"not a packet".to_vec(),
+        ];
+        let mut bytes = vec![];
+        for packet in &packets {
+            encode_frame(Header::default(), packet, &mut bytes);
+        }
+        let section = "path = 'test'\nallowedips = ['10.1.0.2/32', 'fd00::2/128']";
+
+        let (payloads, state) =
+            read_all(Peer::Sock(toml::from_str(section).unwrap()), &bytes).await;
+        assert_eq!(payloads, [&packets[0][..], &packets[2]]);
+        assert_eq!(state.stats.spoofed_packets.load(Ordering::Relaxed), 3);
+
+        let section = format!("{}\nsource-check = false", section);
+        let (payloads, _) = read_all(Peer::Sock(toml::from_str(&section).unwrap()), &bytes).await;
+        assert_eq!(payloads, packets);
+    }
 }
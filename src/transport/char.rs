@@ -1,24 +1,204 @@
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
 use bytes::Bytes;
-use packet::ip::v4::Packet;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::{broadcast, mpsc};
-use tokio_serial::{ClearBuffer, SerialPort, SerialPortBuilderExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Sleep};
+use tokio_serial::{
+    self as serial, ClearBuffer, DataBits, SerialPort, SerialPortBuilder, SerialPortBuilderExt,
+    StopBits,
+};
 use tracing::info;
 
 use crate::config::{CharPeerSection, Peer};
-use crate::streams::handle_stream;
+use crate::streams::{handle_stream, PeerState};
+use crate::types::{FlowControl, Parity};
+use crate::usb;
+
+/// How often to check whether the device got plugged in or out.
+const HOTPLUG_POLL: Duration = Duration::from_millis(500);
 
 pub async fn connect_serial(
     peer: CharPeerSection,
-    broadcast_rx: broadcast::Receiver<Packet<Bytes>>,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
     mspc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
 ) -> anyhow::Result<()> {
-    let mut port =
-        tokio_serial::new(&peer.path, peer.speed.unwrap_or(115200)).open_native_async()?;
-    info!("Connected to {}.", &peer.path);
+    let label = peer.label().to_string();
+    let device = match find_device(&peer) {
+        Some(device) => device,
+        None => {
+            info!("[{}] Waiting for the device to be plugged in...", label);
+            let device = loop {
+                tokio::time::sleep(HOTPLUG_POLL).await;
+                if let Some(device) = find_device(&peer) {
+                    break device;
+                }
+            };
+            // give udev some time to set the permissions up
+            tokio::time::sleep(HOTPLUG_POLL).await;
+            device
+        }
+    };
+    if peer.path.is_none() {
+        info!("[{}] Found {}.", label, device.display());
+    }
+
+    let mut port = builder(&peer, &device).open_native_async()?;
     port.clear(ClearBuffer::All)?;
     port.flush().await?;
+    let port = ReadTimeout::new(port, peer.timeout.map(Duration::from_secs_f64));
+
+    select! {
+        res = handle_stream(port, packet_rx, mspc_tx, Peer::Char(peer), state) => {
+            // reading from a device that's gone fails, but that's not an error
+            if res.is_err() && !device.exists() {
+                info!("[{}] Device was unplugged.", label);
+                return Ok(());
+            }
+            res
+        }
+        _ = wait_for_removal(&device) => {
+            info!("[{}] Device was unplugged.", label);
+            Ok(())
+        }
+    }
+}
+
+fn builder(peer: &CharPeerSection, device: &Path) -> SerialPortBuilder {
+    let data_bits = match peer.data_bits {
+        Some(5) => DataBits::Five,
+        Some(6) => DataBits::Six,
+        Some(7) => DataBits::Seven,
+        _ => DataBits::Eight,
+    };
+    let stop_bits = match peer.stop_bits {
+        Some(2) => StopBits::Two,
+        _ => StopBits::One,
+    };
+    let parity = match peer.parity.unwrap_or_default() {
+        Parity::None => serial::Parity::None,
+        Parity::Odd => serial::Parity::Odd,
+        Parity::Even => serial::Parity::Even,
+    };
+    let flow_control = match peer.flow_control.unwrap_or_default() {
+        FlowControl::None => serial::FlowControl::None,
+        FlowControl::Hardware => serial::FlowControl::Hardware,
+        FlowControl::Software => serial::FlowControl::Software,
+    };
+
+    tokio_serial::new(device.to_string_lossy(), peer.speed.unwrap_or(115200))
+        .data_bits(data_bits)
+        .parity(parity)
+        .stop_bits(stop_bits)
+        .flow_control(flow_control)
+}
+
+/// Returns the device to open, if it's plugged in.
+fn find_device(peer: &CharPeerSection) -> Option<PathBuf> {
+    match &peer.path {
+        Some(path) => Some(PathBuf::from(path)).filter(|p| p.exists()),
+        None => usb::find_device(peer),
+    }
+}
+
+/// Returns once `device` doesn't exist anymore.
+async fn wait_for_removal(device: &Path) {
+    while device.exists() {
+        tokio::time::sleep(HOTPLUG_POLL).await;
+    }
+}
+
+/// A port that fails once nothing was read from it for `timeout`, so that a silent link counts
+/// as disconnected.
+struct ReadTimeout<S> {
+    stream: S,
+    timeout: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ReadTimeout<S> {
+    fn new(stream: S, timeout: Option<Duration>) -> Self {
+        ReadTimeout {
+            stream,
+            timeout,
+            deadline: timeout.map(|t| Box::pin(sleep(t))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ReadTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.stream).poll_read(cx, buf);
+        let (Some(timeout), Some(deadline)) = (this.timeout, &mut this.deadline) else {
+            return res;
+        };
+        match res {
+            Poll::Ready(_) => {
+                deadline
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + timeout);
+                res
+            }
+            Poll::Pending => match deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Nothing read for {:?}", timeout),
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ReadTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn read_timeout() {
+        let (mut near, far) = tokio::io::duplex(64);
+        let mut port = ReadTimeout::new(far, Some(Duration::from_millis(200)));
+        let mut buf = [0; 8];
+        // what keeps coming in keeps it going
+        for _ in 0..4 {
+            sleep(Duration::from_millis(100)).await;
+            near.write_all(b"x").await.unwrap();
+            assert_eq!(port.read(&mut buf).await.unwrap(), 1);
+        }
 
-    handle_stream(port, broadcast_rx, mspc_tx, Peer::Char(peer)).await?;
-    Ok(())
+        let err = port.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
 }
@@ -0,0 +1,96 @@
+use std::os::unix::process::CommandExt;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::config::{ExecPeerSection, Peer};
+use crate::streams::{handle_stream, PeerState};
+use crate::transport::Pipes;
+
+/// How long a command gets to exit after SIGTERM.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn connect_exec(
+    peer: ExecPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+) -> anyhow::Result<()> {
+    let mut command = std::process::Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(&peer.command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // so that whatever the shell starts gets killed along with it
+        .process_group(0);
+    let mut child = Command::from(command).spawn()?;
+    let group = ProcessGroup(child.id().ok_or(anyhow!("Command exited right away"))?);
+    let path = peer.command.clone();
+    info!("[{}] Started command.", path);
+
+    let pipes = Pipes {
+        reader: child.stdout.take().ok_or(anyhow!("No stdout"))?,
+        writer: child.stdin.take().ok_or(anyhow!("No stdin"))?,
+    };
+    if let Some(stderr) = child.stderr.take() {
+        tokio::task::spawn(forward_stderr(stderr, path.clone()));
+    }
+
+    let res = select! {
+        res = handle_stream(pipes, packet_rx, mpsc_tx, Peer::Exec(peer), state) => res,
+        _ = child.wait() => Ok(()),
+    };
+
+    match child.try_wait()? {
+        // the stream most likely broke because of it
+        Some(status) if !status.success() => bail!("Command failed, {}", status),
+        Some(_) => {}
+        None => {
+            group.terminate();
+            if timeout(KILL_TIMEOUT, child.wait()).await.is_err() {
+                warn!("[{}] Command didn't exit, killing it.", path);
+                child.kill().await?;
+            }
+        }
+    }
+    res?;
+
+    Ok(())
+}
+
+/// Logs what the command prints on stderr, one line at a time.
+async fn forward_stderr(stderr: impl AsyncRead + Unpin, path: String) {
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!("[{}] {}", path, line);
+    }
+}
+
+/// The process group of a command, terminated when dropped.
+struct ProcessGroup(u32);
+
+impl ProcessGroup {
+    fn terminate(&self) {
+        // the group has the same id as its leader
+        unsafe {
+            libc::kill(-(self.0 as libc::pid_t), libc::SIGTERM);
+        }
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.terminate();
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::{mpsc, Mutex, OnceCell};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{info, warn};
+
+use crate::config::Peer;
+use crate::framing::{decode_frame, MAX_PAYLOAD};
+use crate::streams::{handle_stream, LinkState, PeerState};
+use crate::transport::{tls, websocket};
+use crate::types::{ConnectionPolicy, Framing, Header};
+use crate::HEADER_SIZE;
+
+/// How long a new connection has to get through TLS and send a frame that tells who it is.
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, which is usually about running out of file descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_secs(1);
+
+/// A connection accepted by a listener, whatever its transport.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// A connection accepted by a listener, ready to carry frames.
+pub enum Accepted {
+    Stream(Box<dyn Connection>),
+    /// Along with the frame that was read to identify the client, if any.
+    WebSocket(Box<WebSocketStream<TcpStream>>, Option<Vec<u8>>),
+}
+
+/// Waits for the listener of a peer to accept a connection from it, and exchanges frames
+/// over it until it breaks, or until a newer connection replaces it.
+pub async fn serve(
+    peer: Peer,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+    listeners: &Listeners,
+) -> anyhow::Result<()> {
+    let (listener, index) = listeners
+        .find(&peer)
+        .ok_or_else(|| anyhow!("No listener for {}", peer.path()))?;
+    listener.start().await?;
+
+    let mut incoming = listener.clients[index].incoming.lock().await;
+    let mut accepted = incoming
+        .recv()
+        .await
+        .ok_or_else(|| anyhow!("Listener is gone"))?;
+    loop {
+        select! {
+            res = run(accepted, packet_rx, mpsc_tx.clone(), peer.clone(), state.clone()) => {
+                return res;
+            }
+            Some(next) = incoming.recv() => {
+                info!("[{}] Replacing the connection with a new one.", peer.path());
+                accepted = next;
+            }
+        }
+    }
+}
+
+async fn run(
+    accepted: Accepted,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    peer: Peer,
+    state: Arc<PeerState>,
+) -> anyhow::Result<()> {
+    match accepted {
+        Accepted::Stream(stream) => handle_stream(stream, packet_rx, mpsc_tx, peer, state).await,
+        Accepted::WebSocket(ws, first) => {
+            let keepalive = match &peer {
+                Peer::WsListen(w) => w.keepalive,
+                _ => None,
+            };
+            websocket::handle_websocket(*ws, first, packet_rx, mpsc_tx, peer, state, keepalive)
+                .await
+        }
+    }
+}
+
+/// The addresses the listening peers listen on, each shared by all the sections of a kind with that `path`.
+pub struct Listeners(HashMap<(&'static str, String), Arc<Listener>>);
+
+impl Listeners {
+    pub fn new(peers: &[Peer], states: &[Arc<PeerState>]) -> Self {
+        let mut listeners: HashMap<_, Listener> = HashMap::new();
+        for (peer, state) in peers.iter().zip(states) {
+            let Some(path) = listen_path(peer) else {
+                continue;
+            };
+            let (tx, rx) = mpsc::channel(1);
+            listeners
+                .entry((peer.section(), path.to_string()))
+                .or_insert_with(|| Listener {
+                    path: path.to_string(),
+                    // the first section sets up TLS and WebSocket for all of them
+                    peer: peer.clone(),
+                    clients: Vec::new(),
+                    started: OnceCell::new(),
+                })
+                .clients
+                .push(Client {
+                    peer: peer.clone(),
+                    state: state.clone(),
+                    tx,
+                    incoming: Mutex::new(rx),
+                });
+        }
+
+        Self(
+            listeners
+                .into_iter()
+                .map(|(key, listener)| (key, Arc::new(listener)))
+                .collect(),
+        )
+    }
+
+    fn find(&self, peer: &Peer) -> Option<(Arc<Listener>, usize)> {
+        let path = listen_path(peer)?;
+        let listener = self.0.get(&(peer.section(), path.to_string()))?;
+        let index = listener
+            .clients
+            .iter()
+            .position(|c| c.peer.public_key() == peer.public_key())?;
+        Some((listener.clone(), index))
+    }
+}
+
+/// The address a peer listens on, if it's a listening one.
+pub fn listen_path(peer: &Peer) -> Option<&str> {
+    match peer {
+        Peer::SockListen(s) => Some(&s.path),
+        Peer::TlsListen(t) => Some(&t.path),
+        Peer::WsListen(w) => Some(&w.path),
+        _ => None,
+    }
+}
+
+fn when_connected(peer: &Peer) -> ConnectionPolicy {
+    match peer {
+        Peer::SockListen(s) => s.when_connected(),
+        Peer::TlsListen(t) => t.when_connected(),
+        Peer::WsListen(w) => w.when_connected(),
+        _ => ConnectionPolicy::default(),
+    }
+}
+
+/// A peer that connects to a listener.
+struct Client {
+    peer: Peer,
+    state: Arc<PeerState>,
+    tx: mpsc::Sender<Accepted>,
+    /// connections accepted for this client
+    incoming: Mutex<mpsc::Receiver<Accepted>>,
+}
+
+/// Keeps accepting connections on an address, and hands each of them to its client.
+struct Listener {
+    path: String,
+    peer: Peer,
+    clients: Vec<Client>,
+    started: OnceCell<()>,
+}
+
+impl Listener {
+    /// Binds the address the first time it's needed, and accepts connections from then on.
+    async fn start(self: &Arc<Self>) -> anyhow::Result<()> {
+        self.started
+            .get_or_try_init(|| async {
+                let tls = match &self.peer {
+                    Peer::TlsListen(t) => Some(tls::acceptor(t)?),
+                    _ => None,
+                };
+                let listener = TcpListener::bind(&self.path).await?;
+                info!("[{}] Listening.", self.path);
+                tokio::task::spawn(self.clone().accept(listener, tls));
+                anyhow::Ok(())
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener, tls: Option<TlsAcceptor>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    let this = self.clone();
+                    let tls = tls.clone();
+                    tokio::task::spawn(async move { this.dispatch(stream, address, tls).await });
+                }
+                Err(e) => {
+                    warn!("[{}] Couldn't accept a connection: {}", self.path, e);
+                    sleep(ACCEPT_RETRY).await;
+                }
+            }
+        }
+    }
+
+    async fn dispatch(&self, stream: TcpStream, address: SocketAddr, tls: Option<TlsAcceptor>) {
+        let identified = timeout(IDENTIFY_TIMEOUT, async {
+            let accepted = match (&self.peer, tls) {
+                (_, Some(tls)) => Accepted::Stream(Box::new(tls.accept(stream).await?)),
+                (Peer::WsListen(w), None) => Accepted::WebSocket(
+                    Box::new(websocket::accept(stream, w.url_path.as_deref()).await?),
+                    None,
+                ),
+                _ => Accepted::Stream(Box::new(stream)),
+            };
+            self.identify(accepted).await
+        });
+        let (client, accepted) = match identified.await {
+            Ok(Ok((index, accepted))) => (&self.clients[index], accepted),
+            Ok(Err(e)) => {
+                warn!(
+                    "[{}] Rejected connection from {}: {}",
+                    self.path, address, e
+                );
+                return;
+            }
+            Err(_) => {
+                warn!(
+                    "[{}] Rejected connection from {}: too slow",
+                    self.path, address
+                );
+                return;
+            }
+        };
+        let path = client.peer.path();
+        if when_connected(&client.peer) == ConnectionPolicy::Reject
+            && client.state.link() == LinkState::Up
+        {
+            info!("[{}] Already connected, rejected {}.", path, address);
+            return;
+        }
+        if client.tx.try_send(accepted).is_err() {
+            info!(
+                "[{}] Busy with another connection, rejected {}.",
+                path, address
+            );
+            return;
+        }
+        info!("[{}] Accepted connection from {}.", path, address);
+    }
+
+    /// Finds out which client a connection comes from, so that nobody else can take its place.
+    ///
+    /// Connections start with a noise handshake that tells their public key, or a keepalive
+    /// sealed with the psk, which is handed back along with the connection so that the client can
+    /// still read it. Bare packets don't say anything, they're taken on trust.
+    async fn identify(&self, accepted: Accepted) -> anyhow::Result<(usize, Accepted)> {
+        if let [client] = &self.clients[..] {
+            if matches!(
+                client.peer.link().framing(),
+                Framing::Slip | Framing::Cslip | Framing::Hdlc
+            ) {
+                return Ok((0, accepted));
+            }
+        }
+
+        match accepted {
+            Accepted::Stream(mut stream) => {
+                let frame = read_frame(&mut stream).await?;
+                let index = self.client_of(&frame)?;
+                let stream = Rewound {
+                    frame,
+                    read: 0,
+                    stream,
+                };
+                Ok((index, Accepted::Stream(Box::new(stream))))
+            }
+            Accepted::WebSocket(mut ws, _) => {
+                let frame = websocket::read_message(&mut ws).await?;
+                let index = self.client_of(&frame)?;
+                Ok((index, Accepted::WebSocket(ws, Some(frame))))
+            }
+        }
+    }
+
+    /// Finds the client that sent the first frame of a connection.
+    fn client_of(&self, frame: &[u8]) -> anyhow::Result<usize> {
+        let (header, payload) = decode_frame(frame).map_err(|_| anyhow!("Corrupt frame"))?;
+        let verified: Vec<_> = self
+            .clients
+            .iter()
+            .map(|c| c.state.crypto.verify(&header, payload))
+            .collect();
+        if let Some(index) = verified.iter().position(Result::is_ok) {
+            return Ok(index);
+        }
+        match &verified[..] {
+            // with a single client, it says what's wrong
+            [Err(e)] => bail!("{}", e),
+            _ => bail!("Unknown peer"),
+        }
+    }
+}
+
+/// Reads a whole frame off a stream.
+async fn read_frame(stream: &mut Box<dyn Connection>) -> anyhow::Result<Vec<u8>> {
+    let mut frame = vec![0u8; HEADER_SIZE];
+    stream.read_exact(&mut frame).await?;
+    let len = Header::from_slice(&frame)?.packet_length as usize;
+    if len > MAX_PAYLOAD {
+        bail!("Frame too long");
+    }
+    frame.resize(HEADER_SIZE + len, 0);
+    stream.read_exact(&mut frame[HEADER_SIZE..]).await?;
+
+    Ok(frame)
+}
+
+/// A stream with the frame that was read from it to identify the client put back in front.
+struct Rewound {
+    frame: Vec<u8>,
+    read: usize,
+    stream: Box<dyn Connection>,
+}
+
+impl AsyncRead for Rewound {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read < self.frame.len() {
+            let n = buf.remaining().min(self.frame.len() - self.read);
+            buf.put_slice(&self.frame[self.read..self.read + n]);
+            self.read += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Rewound {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
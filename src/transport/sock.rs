@@ -1,34 +1,31 @@
+use std::sync::Arc;
+
 use bytes::Bytes;
-use packet::ip::v4::Packet;
-use tokio::sync::{broadcast, mpsc};
-use tracing::info;
+use tokio::sync::mpsc;
 
 use crate::config::{Peer, SockListenPeerSection, SockPeerSection};
-use crate::streams::handle_stream;
+use crate::streams::{handle_stream, PeerState};
+use crate::transport::listener::{self, Listeners};
 
 pub async fn connect_sock(
     peer: SockPeerSection,
-    broadcast_rx: broadcast::Receiver<Packet<Bytes>>,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
     mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
 ) -> anyhow::Result<()> {
     let stream = tokio::net::TcpStream::connect(&peer.path).await?;
-    info!("Connected to {}.", &peer.path);
 
-    handle_stream(stream, broadcast_rx, mpsc_tx, Peer::Sock(peer)).await?;
+    handle_stream(stream, packet_rx, mpsc_tx, Peer::Sock(peer), state).await?;
 
     Ok(())
 }
 
 pub async fn connect_sock_listen(
     peer: SockListenPeerSection,
-    broadcast_rx: broadcast::Receiver<Packet<Bytes>>,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
     mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+    listeners: &Listeners,
 ) -> anyhow::Result<()> {
-    let listener = tokio::net::TcpListener::bind(&peer.path).await?;
-    let (stream, _) = listener.accept().await?;
-    info!("Connected to {}.", &peer.path);
-
-    handle_stream(stream, broadcast_rx, mpsc_tx, Peer::SockListen(peer)).await?;
-
-    Ok(())
+    listener::serve(Peer::SockListen(peer), packet_rx, mpsc_tx, state, listeners).await
 }
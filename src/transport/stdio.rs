@@ -0,0 +1,28 @@
+use std::io;
+use std::os::fd::AsFd;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::fs::File;
+use tokio::sync::mpsc;
+
+use crate::config::{Peer, StdioPeerSection};
+use crate::streams::{handle_stream, PeerState};
+use crate::transport::Pipes;
+
+pub async fn connect_stdio(
+    peer: StdioPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+) -> anyhow::Result<()> {
+    // tokio's stdout is line buffered, files aren't
+    let stdio = Pipes {
+        reader: File::from_std(io::stdin().as_fd().try_clone_to_owned()?.into()),
+        writer: File::from_std(io::stdout().as_fd().try_clone_to_owned()?.into()),
+    };
+
+    handle_stream(stdio, packet_rx, mpsc_tx, Peer::Stdio(peer), state).await?;
+
+    Ok(())
+}
@@ -0,0 +1,286 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::{Peer, TlsListenPeerSection, TlsPeerSection};
+use crate::streams::{handle_stream, PeerState};
+use crate::transport::listener::{self, Listeners};
+
+pub async fn connect_tls(
+    peer: TlsPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+) -> anyhow::Result<()> {
+    let client_auth = peer.cert.as_deref().zip(peer.key.as_deref());
+    let connector = connector(Some(&peer.ca), client_auth)?;
+    let server_name = ServerName::try_from(peer.server_name().to_string())?;
+    let stream = TcpStream::connect(&peer.path).await?;
+    let stream = connector.connect(server_name, stream).await?;
+
+    handle_stream(stream, packet_rx, mpsc_tx, Peer::Tls(peer), state).await?;
+
+    Ok(())
+}
+
+pub async fn connect_tls_listen(
+    peer: TlsListenPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+    listeners: &Listeners,
+) -> anyhow::Result<()> {
+    listener::serve(Peer::TlsListen(peer), packet_rx, mpsc_tx, state, listeners).await
+}
+
+/// Reads the files every time, so that renewed certificates get picked up on reconnection.
+///
+/// Trusts the system's certificate authorities without a `ca`.
+pub fn connector(
+    ca: Option<&str>,
+    client_auth: Option<(&str, &str)>,
+) -> anyhow::Result<TlsConnector> {
+    let roots = match ca {
+        Some(ca) => roots(ca)?,
+        None => system_roots()?,
+    };
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match client_auth {
+        Some((cert, key)) => builder.with_client_auth_cert(certs(cert)?, private_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub fn acceptor(peer: &TlsListenPeerSection) -> anyhow::Result<TlsAcceptor> {
+    let builder = match &peer.client_ca {
+        Some(ca) => ServerConfig::builder()
+            .with_client_cert_verifier(WebPkiClientVerifier::builder(roots(ca)?.into()).build()?),
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs(&peer.cert)?, private_key(&peer.key)?)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn certs(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| anyhow!("{}: {}", path, e))?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("{}: no certificates", path));
+    }
+
+    Ok(certs)
+}
+
+fn private_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| anyhow!("{}: {}", path, e))?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| anyhow!("{}: no private key", path))
+}
+
+fn roots(path: &str) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert)?;
+    }
+
+    Ok(roots)
+}
+
+fn system_roots() -> anyhow::Result<RootCertStore> {
+    let found = rustls_native_certs::load_native_certs();
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(found.certs);
+    if added == 0 {
+        return Err(match found.errors.into_iter().next() {
+            Some(e) => anyhow!("No system certificate authorities: {}", e),
+            None => anyhow!("No system certificate authorities"),
+        });
+    }
+
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use anyhow::bail;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair,
+    };
+    use tokio::select;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::crypto::Crypto;
+
+    /// Certificates in a directory of their own, removed when done.
+    struct Pki(PathBuf);
+
+    impl Pki {
+        /// A CA with a certificate for the server and one for the client, and a client
+        /// certificate from another CA.
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("ip2char-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let pki = Pki(dir);
+            let (ca, ca_key) = pki.authority("ca");
+            pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth, &ca, &ca_key);
+            pki.issue("client", ExtendedKeyUsagePurpose::ClientAuth, &ca, &ca_key);
+            let (rogue, rogue_key) = pki.authority("rogue-ca");
+            pki.issue(
+                "rogue",
+                ExtendedKeyUsagePurpose::ClientAuth,
+                &rogue,
+                &rogue_key,
+            );
+            pki
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.0.join(file).to_string_lossy().into_owned()
+        }
+
+        fn authority(&self, name: &str) -> (Certificate, KeyPair) {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            std::fs::write(self.path(&format!("{}.pem", name)), cert.pem()).unwrap();
+            (cert, key)
+        }
+
+        fn issue(
+            &self,
+            name: &str,
+            usage: ExtendedKeyUsagePurpose,
+            ca: &Certificate,
+            ca_key: &KeyPair,
+        ) {
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![usage];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, ca, ca_key).unwrap();
+            std::fs::write(self.path(&format!("{}.pem", name)), cert.pem()).unwrap();
+            std::fs::write(self.path(&format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// An IPv4 packet from the client, which the listener's reverse path check lets through.
+    fn packet() -> Bytes {
+        let mut packet = vec![
+            0x45, 0, 0, 25, 0, 0, 0, 0, 64, 17, 0, 0, 10, 1, 0, 1, 10, 1, 0, 2,
+        ];
+        packet.extend_from_slice(b"hello");
+        Bytes::from(packet)
+    }
+
+    /// Sends a packet from a client with the `client` certificate, if any, to a listener that
+    /// checks client certificates with `client_ca`, and returns what the listener got.
+    async fn send_through(
+        pki: &Pki,
+        client_ca: bool,
+        client: Option<&str>,
+    ) -> anyhow::Result<Bytes> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let mut listen = format!(
+            "path = \"127.0.0.1:{}\"\ncert = {:?}\nkey = {:?}\nallowedips = [\"10.1.0.1/32\"]\n",
+            port,
+            pki.path("server.pem"),
+            pki.path("server.key")
+        );
+        if client_ca {
+            listen += &format!("client-ca = {:?}\n", pki.path("ca.pem"));
+        }
+        let listen: TlsListenPeerSection = toml::from_str(&listen)?;
+        let mut connect = format!(
+            "path = \"127.0.0.1:{}\"\nserver-name = \"localhost\"\nca = {:?}\nallowedips = [\"10.1.0.2/32\"]\n",
+            port,
+            pki.path("ca.pem")
+        );
+        if let Some(name) = client {
+            connect += &format!(
+                "cert = {:?}\nkey = {:?}\n",
+                pki.path(&format!("{}.pem", name)),
+                pki.path(&format!("{}.key", name))
+            );
+        }
+        let connect: TlsPeerSection = toml::from_str(&connect)?;
+
+        let (listen_tx, mut listen_rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            let state = Arc::new(PeerState::new(Crypto::None, None));
+            let listeners = Listeners::new(
+                &[Peer::TlsListen(listen.clone())],
+                std::slice::from_ref(&state),
+            );
+            let (_packet_tx, mut packet_rx) = mpsc::channel(8);
+            connect_tls_listen(listen, &mut packet_rx, listen_tx, state, &listeners).await
+        });
+        // time to bind
+        sleep(Duration::from_millis(100)).await;
+
+        let (packet_tx, mut packet_rx) = mpsc::channel(8);
+        let (connect_tx, _connect_rx) = mpsc::channel(8);
+        let mut connected = tokio::spawn(async move {
+            let state = Arc::new(PeerState::new(Crypto::None, None));
+            connect_tls(connect, &mut packet_rx, connect_tx, state).await
+        });
+        packet_tx.send(packet()).await?;
+
+        select! {
+            received = listen_rx.recv() => received.ok_or_else(|| anyhow!("Listener is gone")),
+            res = &mut connected => match res? {
+                Ok(()) => bail!("Connection closed"),
+                Err(e) => Err(e),
+            },
+            _ = sleep(Duration::from_secs(5)) => bail!("Nothing came through"),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_go_through() {
+        let pki = Pki::new("tls");
+        assert_eq!(send_through(&pki, false, None).await.unwrap(), packet());
+        assert_eq!(
+            send_through(&pki, false, Some("client")).await.unwrap(),
+            packet()
+        );
+        assert_eq!(
+            send_through(&pki, true, Some("client")).await.unwrap(),
+            packet()
+        );
+    }
+
+    #[tokio::test]
+    async fn untrusted_clients_are_rejected() {
+        let pki = Pki::new("tls-untrusted");
+        let err = send_through(&pki, true, Some("rogue")).await.unwrap_err();
+        assert!(err.to_string().contains("UnknownCA"), "{}", err);
+        let err = send_through(&pki, true, None).await.unwrap_err();
+        assert!(err.to_string().contains("CertificateRequired"), "{}", err);
+    }
+}
@@ -0,0 +1,162 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::mpsc;
+use tracing::{info, trace};
+
+use crate::config::{Peer, UdpPeerSection};
+use crate::framing::{decode_frame, encode_frame, FrameReader, FrameWriter};
+use crate::streams::{handle_frames, PeerState};
+use crate::types::{FrameKind, Header};
+use crate::utils::{lock, DEFAULT_KEEPALIVE};
+use crate::HEADER_SIZE;
+
+/// Where frames get sent, shared between both halves so that the remote can roam.
+type Endpoint = Arc<Mutex<Option<SocketAddr>>>;
+
+pub async fn connect_udp(
+    peer: UdpPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+) -> anyhow::Result<()> {
+    let remote = match &peer.remote {
+        Some(remote) => Some(
+            tokio::net::lookup_host(remote)
+                .await?
+                .next()
+                .ok_or_else(|| anyhow!("{} didn't resolve to any address", remote))?,
+        ),
+        None => None,
+    };
+    let bind = match (peer.bind, remote) {
+        (Some(bind), _) => bind,
+        (None, Some(SocketAddr::V6(_))) => "[::]:0".parse()?,
+        (None, _) => "0.0.0.0:0".parse()?,
+    };
+    let socket = Arc::new(UdpSocket::bind(bind).await?);
+    let endpoint = Arc::new(Mutex::new(remote));
+
+    let reader = UdpReader {
+        socket: socket.clone(),
+        endpoint: endpoint.clone(),
+        // without a remote, the only way to find the peer is to follow its frames
+        roaming: peer.roaming.unwrap_or(false) || remote.is_none(),
+        source: None,
+        buf: vec![0u8; u16::MAX as usize],
+        path: peer.label().to_string(),
+        state: state.clone(),
+    };
+    let writer = UdpWriter {
+        socket: socket.clone(),
+        endpoint,
+        buf: Vec::new(),
+    };
+
+    let keepalive = match (remote, peer.keepalive.unwrap_or(DEFAULT_KEEPALIVE)) {
+        (Some(remote), interval) if interval > 0.0 => Some((remote, interval)),
+        _ => None,
+    };
+    let frames = handle_frames(reader, writer, packet_rx, mpsc_tx, Peer::Udp(peer), state);
+    match keepalive {
+        Some((remote, interval)) => select! {
+            res = frames => res,
+            res = send_keepalives(&socket, remote, Duration::from_secs_f64(interval)) => res,
+        },
+        None => frames.await,
+    }
+}
+
+/// Lets the peer know where to send its frames, and keeps NAT mappings open.
+async fn send_keepalives(
+    socket: &UdpSocket,
+    remote: SocketAddr,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let header = Header {
+        kind: FrameKind::Keepalive,
+        ..Default::default()
+    };
+    let mut frame = Vec::with_capacity(HEADER_SIZE);
+    encode_frame(header, &[], &mut frame);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        socket.send_to(&frame, remote).await?;
+    }
+}
+
+/// One frame per datagram, so there's nothing to resync.
+struct UdpReader {
+    socket: Arc<UdpSocket>,
+    endpoint: Endpoint,
+    roaming: bool,
+    /// where the last frame came from
+    source: Option<SocketAddr>,
+    buf: Vec<u8>,
+    path: String,
+    state: Arc<PeerState>,
+}
+
+impl FrameReader for UdpReader {
+    async fn read_frame(&mut self, payload: &mut Vec<u8>) -> anyhow::Result<Header> {
+        loop {
+            let (len, source) = self.socket.recv_from(&mut self.buf).await?;
+            if !self.roaming && Some(source) != *lock(&self.endpoint) {
+                trace!("[{}] Ignored datagram from {}", self.path, source);
+                continue;
+            }
+            let (header, data) = match decode_frame(&self.buf[..len]) {
+                Ok(frame) => frame,
+                Err(bad) => {
+                    self.state.drop_frame(&self.path, bad);
+                    continue;
+                }
+            };
+            payload.clear();
+            payload.extend_from_slice(data);
+            self.source = Some(source);
+            return Ok(header);
+        }
+    }
+
+    fn accept(&mut self) {
+        // only genuine frames can set or move the endpoint, or anyone could redirect the traffic
+        let mut endpoint = lock(&self.endpoint);
+        if let Some(source) = self
+            .source
+            .filter(|s| self.roaming && *endpoint != Some(*s))
+        {
+            match *endpoint {
+                Some(_) => info!("[{}] Remote endpoint is now {}.", self.path, source),
+                None => info!("[{}] Remote endpoint is {}.", self.path, source),
+            }
+            *endpoint = Some(source);
+        }
+    }
+}
+
+struct UdpWriter {
+    socket: Arc<UdpSocket>,
+    endpoint: Endpoint,
+    buf: Vec<u8>,
+}
+
+impl FrameWriter for UdpWriter {
+    async fn write_frame(&mut self, header: Header, payload: &[u8]) -> anyhow::Result<()> {
+        let Some(endpoint) = *lock(&self.endpoint) else {
+            trace!("Remote endpoint isn't known yet, dropped frame");
+            return Ok(());
+        };
+        self.buf.clear();
+        encode_frame(header, payload, &mut self.buf);
+        self.socket.send_to(&self.buf, endpoint).await?;
+        Ok(())
+    }
+}
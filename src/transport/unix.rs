@@ -0,0 +1,213 @@
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, OnceCell};
+use tracing::{info, warn};
+
+use crate::config::{Peer, UnixListenPeerSection, UnixPeerSection};
+use crate::streams::{handle_stream, PeerState};
+
+pub async fn connect_unix(
+    peer: UnixPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+) -> anyhow::Result<()> {
+    let stream = UnixStream::connect(&peer.path).await?;
+
+    handle_stream(stream, packet_rx, mpsc_tx, Peer::Unix(peer), state).await?;
+
+    Ok(())
+}
+
+/// Accepts a connection on the socket, which is created the first time and then kept in
+/// `socket` from one connection to the next.
+pub async fn connect_unix_listen(
+    peer: UnixListenPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+    socket: &OnceCell<Socket>,
+) -> anyhow::Result<()> {
+    let socket = socket
+        .get_or_try_init(|| async { Socket::bind(&peer) })
+        .await?;
+    let (stream, _) = socket.listener.accept().await?;
+
+    handle_stream(stream, packet_rx, mpsc_tx, Peer::UnixListen(peer), state).await?;
+
+    Ok(())
+}
+
+/// A listening socket, whose file is removed when it's dropped.
+pub struct Socket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Socket {
+    /// Binds the socket in a private directory next to its path, and only moves it there once
+    /// its mode and group are set, so that nobody can connect in between.
+    fn bind(peer: &UnixListenPeerSection) -> anyhow::Result<Self> {
+        let path = Path::new(&peer.path);
+        check_parent(path)?;
+        remove_stale_socket(path)?;
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} isn't a file name", path.display()))?;
+        let mut private = OsString::from(".");
+        private.push(name);
+        private.push(format!(".{}", std::process::id()));
+        let private = path.with_file_name(private);
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+        let bound = (|| {
+            let temporary = private.join("socket");
+            let listener = UnixListener::bind(&temporary)?;
+            if let Some(mode) = &peer.mode {
+                let mode = parse_mode(mode).ok_or_else(|| anyhow!("Invalid mode {}", mode))?;
+                fs::set_permissions(&temporary, fs::Permissions::from_mode(mode))?;
+            }
+            if let Some(group) = &peer.group {
+                let gid = group_id(group).ok_or_else(|| anyhow!("No such group {}", group))?;
+                std::os::unix::fs::chown(&temporary, None, Some(gid))?;
+            }
+            fs::rename(&temporary, path)?;
+            anyhow::Ok(listener)
+        })();
+        if let Err(e) = fs::remove_dir_all(&private) {
+            warn!("Couldn't remove {}: {}", private.display(), e);
+        }
+
+        Ok(Socket {
+            listener: bound?,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Couldn't remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Refuses a directory where anyone could swap the socket for their own, since the sticky bit
+/// is what keeps others from renaming or removing it in world-writable places like /tmp.
+fn check_parent(path: &Path) -> anyhow::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mode = fs::metadata(parent)?.permissions().mode();
+    if mode & 0o002 != 0 && mode & 0o1000 == 0 {
+        bail!(
+            "{} is world-writable without the sticky bit, anyone could replace the socket",
+            parent.display()
+        );
+    }
+    Ok(())
+}
+
+/// Removes a socket left behind by a process that's gone, but not one that's still in use.
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+        Ok(meta) if !meta.file_type().is_socket() => {
+            bail!("{} already exists and isn't a socket", path.display())
+        }
+        Ok(_) => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                bail!("{} is in use by another process", path.display());
+            }
+            info!("Removing stale socket {}.", path.display());
+            fs::remove_file(path)?;
+            Ok(())
+        }
+    }
+}
+
+/// Parses permissions written in octal, like `660` or `0660`.
+pub fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode, 8).ok().filter(|&m| m <= 0o777)
+}
+
+/// Looks a group up by name, or takes it as a numeric ID.
+pub fn group_id(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid);
+    }
+    let name = CString::new(group).ok()?;
+    // getgrnam isn't reentrant, but the result is copied out right away
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return None;
+    }
+    Some(unsafe { (*entry).gr_gid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn socket_file() {
+        let dir = std::env::temp_dir().join(format!("ip2char-unix-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("link.sock");
+        let peer: UnixListenPeerSection = toml::from_str(&format!(
+            "path = {:?}\nmode = \"600\"\nallowedips = []",
+            path.display().to_string()
+        ))
+        .unwrap();
+
+        let socket = Socket::bind(&peer).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // only the socket is left, not the directory it was bound in
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // it keeps accepting connections
+        for _ in 0..2 {
+            let _client = UnixStream::connect(&path).await.unwrap();
+            socket.listener.accept().await.unwrap();
+        }
+        assert!(Socket::bind(&peer).is_err());
+
+        drop(socket);
+        assert!(!path.exists());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn world_writable_parent() {
+        let dir = std::env::temp_dir().join(format!("ip2char-unix-open-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("link.sock");
+        let peer: UnixListenPeerSection = toml::from_str(&format!(
+            "path = {:?}\nallowedips = []",
+            path.display().to_string()
+        ))
+        .unwrap();
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(Socket::bind(&peer).is_err());
+        assert!(!path.exists());
+
+        // like /tmp
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        drop(Socket::bind(&peer).unwrap());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
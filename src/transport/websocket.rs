@@ -0,0 +1,293 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::{mpsc, Mutex};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{StatusCode, Uri};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::config::{Peer, WsListenPeerSection, WsPeerSection};
+use crate::framing::{decode_frame, encode_frame, FrameReader, FrameWriter};
+use crate::streams::{handle_frames, PeerState};
+use crate::transport::listener::{self, Listeners};
+use crate::transport::tls;
+use crate::types::Header;
+use crate::utils::DEFAULT_KEEPALIVE;
+use crate::HEADER_SIZE;
+
+pub async fn connect_ws(
+    peer: WsPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+) -> anyhow::Result<()> {
+    let uri: Uri = peer.url.parse()?;
+    let secure = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => bail!("{} isn't a ws:// or wss:// URL", peer.url),
+    };
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow!("{} has no host", peer.url))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+    let stream = TcpStream::connect((host, port)).await?;
+
+    let keepalive = peer.keepalive;
+    let url = peer.url.clone();
+    let peer_ = Peer::Ws(peer.clone());
+    if secure {
+        let connector = tls::connector(peer.ca.as_deref(), None)?;
+        let stream = connector
+            .connect(ServerName::try_from(host.to_string())?, stream)
+            .await?;
+        let (ws, _) = tokio_tungstenite::client_async(url, stream).await?;
+        handle_websocket(ws, None, packet_rx, mpsc_tx, peer_, state, keepalive).await
+    } else {
+        let (ws, _) = tokio_tungstenite::client_async(url, stream).await?;
+        handle_websocket(ws, None, packet_rx, mpsc_tx, peer_, state, keepalive).await
+    }
+}
+
+pub async fn connect_ws_listen(
+    peer: WsListenPeerSection,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    state: Arc<PeerState>,
+    listeners: &Listeners,
+) -> anyhow::Result<()> {
+    listener::serve(Peer::WsListen(peer), packet_rx, mpsc_tx, state, listeners).await
+}
+
+/// Completes the handshake of an incoming WebSocket connection, if it's for `url_path`.
+pub async fn accept(
+    stream: TcpStream,
+    url_path: Option<&str>,
+) -> anyhow::Result<WebSocketStream<TcpStream>> {
+    // the callback's signature is up to tungstenite
+    #[allow(clippy::result_large_err)]
+    let check = |request: &Request, response: Response| match url_path {
+        Some(path) if request.uri().path() != path => {
+            let mut error = ErrorResponse::new(None);
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            Err(error)
+        }
+        _ => Ok(response),
+    };
+
+    Ok(tokio_tungstenite::accept_hdr_async(stream, check).await?)
+}
+
+/// Reads the next frame, for listeners to find out who's connecting.
+pub async fn read_message<S>(ws: &mut WebSocketStream<S>) -> anyhow::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        match ws.next().await {
+            Some(Ok(Message::Binary(data))) => return Ok(data),
+            Some(Ok(Message::Close(_))) | None => bail!("Connection closed"),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+/// Exchanges frames with a peer over a WebSocket, starting with `first` if a listener already read it.
+pub async fn handle_websocket<S>(
+    ws: WebSocketStream<S>,
+    first: Option<Vec<u8>>,
+    packet_rx: &mut mpsc::Receiver<Bytes>,
+    mpsc_tx: mpsc::Sender<Bytes>,
+    peer: Peer,
+    state: Arc<PeerState>,
+    keepalive: Option<f64>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let keepalive = keepalive.unwrap_or(DEFAULT_KEEPALIVE);
+    let (sink, stream) = ws.split();
+    let sink = Arc::new(Mutex::new(sink));
+    let reader = WsReader {
+        stream,
+        first,
+        path: peer.path().to_string(),
+        state: state.clone(),
+    };
+    let writer = WsWriter { sink: sink.clone() };
+
+    let frames = handle_frames(reader, writer, packet_rx, mpsc_tx, peer, state);
+    if keepalive == 0.0 {
+        return frames.await;
+    }
+    select! {
+        res = frames => res,
+        res = send_pings(&sink, Duration::from_secs_f64(keepalive)) => res,
+    }
+}
+
+async fn send_pings<S>(
+    sink: &Mutex<SplitSink<WebSocketStream<S>, Message>>,
+    interval: Duration,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        sink.lock().await.send(Message::Ping(Vec::new())).await?;
+    }
+}
+
+/// One frame per binary message, so there's nothing to resync.
+struct WsReader<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    first: Option<Vec<u8>>,
+    path: String,
+    state: Arc<PeerState>,
+}
+
+impl<S> FrameReader for WsReader<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn read_frame(&mut self, payload: &mut Vec<u8>) -> anyhow::Result<Header> {
+        loop {
+            let message = match self.first.take() {
+                Some(message) => message,
+                None => match self.stream.next().await {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Close(_))) | None => bail!("Connection closed"),
+                    // pings get answered by tungstenite
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                },
+            };
+            let (header, data) = match decode_frame(&message) {
+                Ok(frame) => frame,
+                Err(bad) => {
+                    self.state.drop_frame(&self.path, bad);
+                    continue;
+                }
+            };
+            payload.clear();
+            payload.extend_from_slice(data);
+            return Ok(header);
+        }
+    }
+}
+
+struct WsWriter<S> {
+    sink: Arc<Mutex<SplitSink<WebSocketStream<S>, Message>>>,
+}
+
+impl<S> FrameWriter for WsWriter<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn write_frame(&mut self, header: Header, payload: &[u8]) -> anyhow::Result<()> {
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        encode_frame(header, payload, &mut frame);
+        self.sink.lock().await.send(Message::Binary(frame)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::select;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::crypto::Crypto;
+
+    const PSK: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+
+    /// An IPv4 packet from the client, which the listener's reverse path check lets through.
+    fn packet() -> Bytes {
+        let mut packet = vec![
+            0x45, 0, 0, 25, 0, 0, 0, 0, 64, 17, 0, 0, 10, 1, 0, 1, 10, 1, 0, 2,
+        ];
+        packet.extend_from_slice(b"hello");
+        Bytes::from(packet)
+    }
+
+    /// Sends a packet from a client connecting to `url_path` to a listener on `/tunnel`,
+    /// both using the same psk, and returns what the listener got.
+    async fn send_through(url_path: &str) -> anyhow::Result<Bytes> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let encryption = format!("encryption = \"chacha20poly1305\"\npsk = \"{}\"\n", PSK);
+        let listen = Peer::WsListen(toml::from_str(&format!(
+            "path = \"127.0.0.1:{}\"\nurl-path = \"/tunnel\"\nallowedips = [\"10.1.0.1/32\"]\n{}",
+            port, encryption
+        ))?);
+        let connect = Peer::Ws(toml::from_str(&format!(
+            "url = \"ws://127.0.0.1:{}{}\"\nallowedips = [\"10.1.0.2/32\"]\n{}",
+            port, url_path, encryption
+        ))?);
+
+        let (listen_tx, mut listen_rx) = mpsc::channel(8);
+        let state = Arc::new(PeerState::new(Crypto::new(&listen, None)?, None));
+        tokio::spawn(async move {
+            let listeners =
+                Listeners::new(std::slice::from_ref(&listen), std::slice::from_ref(&state));
+            let Peer::WsListen(listen) = listen else {
+                unreachable!()
+            };
+            let (_packet_tx, mut packet_rx) = mpsc::channel(8);
+            connect_ws_listen(listen, &mut packet_rx, listen_tx, state, &listeners).await
+        });
+        // time to bind
+        sleep(Duration::from_millis(100)).await;
+
+        let (packet_tx, mut packet_rx) = mpsc::channel(8);
+        let (connect_tx, _connect_rx) = mpsc::channel(8);
+        let state = Arc::new(PeerState::new(Crypto::new(&connect, None)?, None));
+        let Peer::Ws(connect) = connect else {
+            unreachable!()
+        };
+        let mut connected =
+            tokio::spawn(
+                async move { connect_ws(connect, &mut packet_rx, connect_tx, state).await },
+            );
+        packet_tx.send(packet()).await?;
+
+        select! {
+            received = listen_rx.recv() => received.ok_or_else(|| anyhow!("Listener is gone")),
+            res = &mut connected => match res? {
+                Ok(()) => bail!("Connection closed"),
+                Err(e) => Err(e),
+            },
+            _ = sleep(Duration::from_secs(5)) => bail!("Nothing came through"),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_go_through() {
+        assert_eq!(send_through("/tunnel").await.unwrap(), packet());
+    }
+
+    #[tokio::test]
+    async fn other_paths_are_rejected() {
+        let err = send_through("/other").await.unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+}
@@ -1,3 +1,6 @@
+use std::process::Command;
+
+use anyhow::bail;
 use tokio_util::codec::Framed;
 use tracing::info;
 use tun::{AsyncDevice, TunPacketCodec};
@@ -21,6 +24,21 @@ pub fn create_tun(config: &Config) -> anyhow::Result<Framed<AsyncDevice, TunPack
     });
 
     let dev = tun::create_as_async(&tun_config)?;
+    // the tun crate can only assign IPv4 addresses
+    for address in &config.interface.address6 {
+        let status = Command::new("ip")
+            .args(["-6", "addr", "add", &address.to_string()])
+            .args(["dev", &config.interface.name])
+            .status()?;
+        if !status.success() {
+            bail!(
+                "Couldn't assign {} to {}: {}",
+                address,
+                config.interface.name,
+                status
+            );
+        }
+    }
     let framed = dev.into_framed();
     info!("[1] Created tun interface.");
     Ok(framed)
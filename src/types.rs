@@ -1,11 +1,15 @@
+use std::fmt;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use bytemuck::from_bytes;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 use tracing::{error, info};
 
 use crate::{utils, HEADER_SIZE};
 
-pub const VERSION: u16 = 0;
+pub const VERSION: u16 = 3;
 pub const MARKER_SIZE: usize = 4;
 pub const SYNC_MARKER: [u8; MARKER_SIZE] = [0xac, 0xab, 0xc0, 0xde];
 
@@ -17,7 +21,12 @@ pub struct Header {
     pub packet_length: u16,
     pub compression: CompressionType,
     pub encryption: EncryptionType,
-    _reserved: [u8; 6],
+    pub kind: FrameKind,
+    /// Identifies the session keys the frame is encrypted with.
+    pub key_id: u8,
+    pub crc: u32,
+    /// Frame counter, used as nonce for encrypted frames.
+    pub counter: u64,
 }
 
 impl Header {
@@ -30,18 +39,40 @@ impl Header {
             return Err(IntoErrors::BadSyncMarker.into());
         }
         let version = *from_bytes::<u16>(&slice[4..6]);
+        if version != VERSION {
+            return Err(IntoErrors::OtherVersion(version).into());
+        }
         let packet_length = *from_bytes::<u16>(&slice[6..8]);
         let compression = slice[8].try_into()?;
         let encryption = slice[9].try_into()?;
+        let kind = slice[10].try_into()?;
+        let key_id = slice[11];
+        let crc = u32::from_le_bytes(slice[12..16].try_into()?);
+        let counter = u64::from_le_bytes(slice[16..24].try_into()?);
         Ok(Self {
             marker,
             version,
             packet_length,
             compression,
             encryption,
-            _reserved: [0; 6],
+            kind,
+            key_id,
+            crc,
+            counter,
         })
     }
+
+    /// CRC32 of the header (with the crc field zeroed) followed by the payload.
+    pub fn checksum(&self, payload: &[u8]) -> u32 {
+        let mut header = *self;
+        header.crc = 0;
+        let header_buf: [u8; HEADER_SIZE] = header.into();
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header_buf);
+        hasher.update(payload);
+        hasher.finalize()
+    }
 }
 
 impl From<Header> for [u8; HEADER_SIZE] {
@@ -52,7 +83,10 @@ impl From<Header> for [u8; HEADER_SIZE] {
         buf[6..8].copy_from_slice(&val.packet_length.to_le_bytes());
         buf[8] = val.compression as u8;
         buf[9] = val.encryption as u8;
-        buf[10..16].copy_from_slice(&val._reserved);
+        buf[10] = val.kind as u8;
+        buf[11] = val.key_id;
+        buf[12..16].copy_from_slice(&val.crc.to_le_bytes());
+        buf[16..24].copy_from_slice(&val.counter.to_le_bytes());
         buf
     }
 }
@@ -65,7 +99,10 @@ impl Default for Header {
             packet_length: 0,
             compression: Default::default(),
             encryption: Default::default(),
-            _reserved: Default::default(),
+            kind: Default::default(),
+            key_id: 0,
+            crc: 0,
+            counter: 0,
         }
     }
 }
@@ -84,6 +121,56 @@ pub enum CompressionType {
     Gzip = 4,
 }
 
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    #[default]
+    None,
+    /// RTS/CTS
+    #[serde(alias = "rts-cts")]
+    Hardware,
+    /// XON/XOFF
+    #[serde(alias = "xon-xoff")]
+    Software,
+}
+
+/// What a listener does with a new connection from a peer that's already connected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionPolicy {
+    /// Drop the old connection, which may well be dead already.
+    #[default]
+    Replace,
+    /// Keep the old connection, and close the new one.
+    Reject,
+}
+
+/// How frames are delimited on a byte stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    /// ip2char's own headers, found again by their sync marker after a desync.
+    #[default]
+    Raw,
+    /// Bare IP packets between SLIP (RFC 1055) END bytes, to talk to `slattach` and embedded stacks.
+    Slip,
+    /// SLIP with Van Jacobson TCP/IP header compression (RFC 1144).
+    Cslip,
+    /// ip2char's frames delimited by zero bytes with COBS, once the peer agrees to it.
+    Cobs,
+    /// Bare IP packets over PPP in HDLC-like frames (RFC 1662), to talk to pppd and routers.
+    Hdlc,
+}
+
 #[derive(Error, Debug)]
 pub enum IntoErrors {
     #[error("no variant exists for integer {0}")]
@@ -94,6 +181,12 @@ pub enum IntoErrors {
 
     #[error("Buffer too small!")]
     BufferTooSmall,
+
+    #[error("peer speaks protocol v{0}, not v{}", VERSION)]
+    OtherVersion(u16),
+
+    #[error("Invalid key: {0}")]
+    BadKey(String),
 }
 
 impl TryInto<CompressionType> for u8 {
@@ -111,12 +204,16 @@ impl TryInto<CompressionType> for u8 {
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 #[repr(u8)]
 #[serde(rename_all = "lowercase")]
 pub enum EncryptionType {
     #[default]
     None = 0,
+    /// ChaCha20-Poly1305 with a pre-shared key.
+    ChaCha20Poly1305 = 1,
+    /// ChaCha20-Poly1305 with session keys from a Noise IK handshake.
+    Noise = 2,
 }
 
 impl TryInto<EncryptionType> for u8 {
@@ -125,11 +222,84 @@ impl TryInto<EncryptionType> for u8 {
     fn try_into(self) -> Result<EncryptionType, Self::Error> {
         match self {
             0 => Ok(EncryptionType::None),
+            1 => Ok(EncryptionType::ChaCha20Poly1305),
+            2 => Ok(EncryptionType::Noise),
             n => Err(IntoErrors::NoSuchVariant(n)),
         }
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// An IP packet.
+    #[default]
+    Data = 0,
+    /// A Noise handshake message.
+    Handshake = 1,
+    /// Empty frame that lets the other end know where the peer is.
+    Keepalive = 2,
+}
+
+impl TryInto<FrameKind> for u8 {
+    type Error = IntoErrors;
+
+    fn try_into(self) -> Result<FrameKind, Self::Error> {
+        match self {
+            0 => Ok(FrameKind::Data),
+            1 => Ok(FrameKind::Handshake),
+            2 => Ok(FrameKind::Keepalive),
+            n => Err(IntoErrors::NoSuchVariant(n)),
+        }
+    }
+}
+
+pub const KEY_SIZE: usize = 32;
+
+/// A 256-bit key, base64-encoded in the configuration file.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Key(pub [u8; KEY_SIZE]);
+
+impl TryFrom<&str> for Key {
+    type Error = IntoErrors;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let bytes = BASE64
+            .decode(value.trim())
+            .map_err(|e| IntoErrors::BadKey(e.to_string()))?;
+        let key = bytes
+            .try_into()
+            .map_err(|_| IntoErrors::BadKey(format!("expected {} bytes", KEY_SIZE)))?;
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&BASE64.encode(self.0))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // don't leak secrets in logs
+        f.write_str("Key(..)")
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Key::try_from(s.as_str()).map_err(serde::de::Error::custom)
+    }
+}
+
 pub struct PostCommand {
     post_down: Option<String>,
 }
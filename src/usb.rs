@@ -0,0 +1,244 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+use crate::config::CharPeerSection;
+
+// relative to the root of the filesystem
+const TTY_CLASS: &str = "sys/class/tty";
+const BY_ID: &str = "dev/serial/by-id";
+const DEV: &str = "dev";
+
+/// Finds the serial device matching the USB selectors of `peer`, if it's plugged in.
+pub fn find_device(peer: &CharPeerSection) -> Option<PathBuf> {
+    find_device_in(Path::new("/"), peer)
+}
+
+fn find_device_in(root: &Path, peer: &CharPeerSection) -> Option<PathBuf> {
+    let mut found = match &peer.by_id {
+        Some(pattern) => find_by_id(root, pattern),
+        None => find_by_identity(root, peer),
+    };
+
+    found.sort();
+    if found.len() > 1 {
+        warn!(
+            "[{}] {} devices match, using {}.",
+            peer.label(),
+            found.len(),
+            found[0].display()
+        );
+    }
+    found.into_iter().next()
+}
+
+fn find_by_id(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(root.join(BY_ID)) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| glob_match(pattern.as_bytes(), e.file_name().as_encoded_bytes()))
+        .map(|e| e.path())
+        // skip dangling links
+        .filter(|path| path.exists())
+        .collect()
+}
+
+fn find_by_identity(root: &Path, peer: &CharPeerSection) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(root.join(TTY_CLASS)) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| usb_device(&e.path()).is_some_and(|device| matches(peer, &device)))
+        .map(|e| root.join(DEV).join(e.file_name()))
+        .collect()
+}
+
+/// Walks up from a tty in sysfs to the USB device it belongs to.
+fn usb_device(tty: &Path) -> Option<PathBuf> {
+    let device = fs::canonicalize(tty.join("device")).ok()?;
+    device
+        .ancestors()
+        .take(4)
+        .find(|dir| dir.join("idVendor").exists())
+        .map(Path::to_path_buf)
+}
+
+fn matches(peer: &CharPeerSection, device: &Path) -> bool {
+    let attribute = |name: &str| {
+        fs::read_to_string(device.join(name))
+            .map(|value| value.trim().to_string())
+            .ok()
+    };
+
+    // IDs are hex, so case doesn't matter for them
+    let id_matches = |wanted: &Option<String>, name: &str| match wanted {
+        Some(wanted) => attribute(name).is_some_and(|id| id.eq_ignore_ascii_case(wanted)),
+        None => true,
+    };
+
+    id_matches(&peer.usb_vid, "idVendor")
+        && id_matches(&peer.usb_pid, "idProduct")
+        && match &peer.usb_serial {
+            Some(wanted) => attribute("serial").as_ref() == Some(wanted),
+            None => true,
+        }
+}
+
+/// Shell-style matching, where `*` matches any run of characters and `?` any single one.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name))) => glob_match(rest, name),
+        (Some((p, rest)), Some((n, name))) if p == n => glob_match(rest, name),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn globs() {
+        let name = "usb-Raspberry_Pi_Pico_E6614C311B4B5A2F-if00";
+        for (pattern, expected) in [
+            (name, true),
+            ("*", true),
+            ("usb-Raspberry_Pi_Pico_*", true),
+            ("*-if00", true),
+            ("*Pico*if0?", true),
+            ("usb-*_E6614C311B4B5A2F-if0?", true),
+            ("usb-Raspberry_Pi_Pico_E6614C311B4B5A2F-if00?", false),
+            ("usb-Raspberry_Pi_Pico_E6614C311B4B5A2F-if0", false),
+            ("*-if01", false),
+            ("?sb-*", true),
+            ("??sb-*", false),
+            ("usb-raspberry*", false),
+            ("", false),
+        ] {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), name.as_bytes()),
+                expected,
+                "{}",
+                pattern
+            );
+        }
+        assert!(glob_match(b"*", b""));
+        assert!(!glob_match(b"?", b""));
+    }
+
+    /// A fake root with sysfs entries and by-id links for some USB serial devices.
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("ip2char-usb-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join(TTY_CLASS)).unwrap();
+            fs::create_dir_all(root.join(BY_ID)).unwrap();
+            Root(root)
+        }
+
+        /// Plugs in `tty`, on a USB device with those attributes.
+        fn plug(&self, tty: &str, port: &str, attributes: &[(&str, &str)], by_id: &str) {
+            let device = self.0.join("sys/devices/usb1").join(port);
+            let interface = device.join(format!("{}:1.0", port));
+            fs::create_dir_all(&interface).unwrap();
+            for (name, value) in attributes {
+                fs::write(device.join(name), format!("{}\n", value)).unwrap();
+            }
+
+            let class = self.0.join(TTY_CLASS).join(tty);
+            fs::create_dir_all(&class).unwrap();
+            symlink(&interface, class.join("device")).unwrap();
+
+            fs::write(self.0.join(DEV).join(tty), "").unwrap();
+            symlink(format!("../../{}", tty), self.0.join(BY_ID).join(by_id)).unwrap();
+        }
+
+        fn find(&self, selectors: &str) -> Option<String> {
+            let peer: CharPeerSection =
+                toml::from_str(&format!("allowedips = []\n{}", selectors)).unwrap();
+            let found = find_device_in(&self.0, &peer)?;
+            Some(found.strip_prefix(&self.0).unwrap().display().to_string())
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn finds_devices() {
+        let root = Root::new("find");
+        root.plug(
+            "ttyACM0",
+            "1-1",
+            &[
+                ("idVendor", "2e8a"),
+                ("idProduct", "000a"),
+                ("serial", "E661"),
+            ],
+            "usb-Raspberry_Pi_Pico_E661-if00",
+        );
+        root.plug(
+            "ttyACM1",
+            "1-2",
+            &[
+                ("idVendor", "2E8A"),
+                ("idProduct", "000a"),
+                ("serial", "E662"),
+            ],
+            "usb-Raspberry_Pi_Pico_E662-if00",
+        );
+        root.plug(
+            "ttyUSB0",
+            "1-3",
+            &[("idVendor", "0403"), ("idProduct", "6001")],
+            "usb-FTDI_FT232R_USB_UART-if00-port0",
+        );
+        // not a USB device
+        fs::create_dir_all(root.0.join(TTY_CLASS).join("ttyS0")).unwrap();
+
+        let find = |selectors| root.find(selectors);
+        assert_eq!(find("usb-vid = '0403'").as_deref(), Some("dev/ttyUSB0"));
+        assert_eq!(find("usb-pid = '6001'").as_deref(), Some("dev/ttyUSB0"));
+        // the first one when several match, whatever the case of the IDs
+        assert_eq!(find("usb-vid = '2e8a'").as_deref(), Some("dev/ttyACM0"));
+        assert_eq!(
+            find("usb-vid = '2E8A'\nusb-pid = '000A'\nusb-serial = 'E662'").as_deref(),
+            Some("dev/ttyACM1")
+        );
+        // serial numbers aren't hex, so they're compared as they are
+        assert_eq!(find("usb-serial = 'e662'"), None);
+        assert_eq!(find("usb-vid = '0403'\nusb-pid = '000a'"), None);
+
+        assert_eq!(
+            find("by-id = '*Pico_E662*'").as_deref(),
+            Some("dev/serial/by-id/usb-Raspberry_Pi_Pico_E662-if00")
+        );
+        assert_eq!(
+            find("by-id = 'usb-FTDI_*-port?'").as_deref(),
+            Some("dev/serial/by-id/usb-FTDI_FT232R_USB_UART-if00-port0")
+        );
+        assert_eq!(find("by-id = '*Arduino*'"), None);
+
+        // unplugged, its by-id link dangles
+        fs::remove_file(root.0.join(DEV).join("ttyUSB0")).unwrap();
+        assert_eq!(find("by-id = 'usb-FTDI_*'"), None);
+    }
+}
@@ -1,38 +1,31 @@
-use ipnetwork::IpNetwork;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
 use tokio::process::Command;
-use tokio::signal;
-use tracing::{error, info};
 
 use crate::config::Peer;
-use std::net::Ipv4Addr;
-use std::process::exit;
+use std::net::IpAddr;
+
+/// Like Wireguard's recommended persistent keepalive, and well under the idle timeouts of common
+/// reverse proxies.
+pub const DEFAULT_KEEPALIVE: f64 = 25.0;
 
-pub fn check_peer_allowed_ip(ip: &Ipv4Addr, peer: &Peer) -> bool {
-    let mut allowed = false;
-    for range in peer.allowed_ips().iter() {
-        // TODO: investigate IPv6 support
-        if let IpNetwork::V4(a) = range {
-            if a.contains(*ip) {
-                allowed = true;
-            }
-        }
-    }
+/// Locks a mutex, going on with what's inside if a thread panicked while holding it.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-    allowed
+pub fn check_peer_allowed_ip(ip: &IpAddr, peer: &Peer) -> bool {
+    peer.allowed_ips().iter().any(|range| range.contains(*ip))
 }
 
 pub fn run_command(command_str: &str) -> anyhow::Result<()> {
     let mut command = Command::new("/bin/sh");
-    command.arg("-c").arg(command_str).spawn()?;
+    // keep stdout clean for peer-stdio
+    command
+        .arg("-c")
+        .arg(command_str)
+        .stdout(std::io::stderr())
+        .spawn()?;
 
     Ok(())
 }
-
-pub async fn handle_post_down_command_sigint(post_down: String) -> anyhow::Result<()> {
-    signal::ctrl_c().await?;
-    match run_command(&post_down) {
-        Ok(_) => info!("post-down: {}", &post_down),
-        Err(e) => error!("post-down: {}", e),
-    }
-    exit(0);
-}
@@ -0,0 +1,1098 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use ipnetwork::IpNetwork;
+use tokio_tungstenite::tungstenite::http::Uri;
+
+use crate::config::{Config, Peer};
+use crate::fec::MAX_ERRORS;
+use crate::transport::listener::listen_path;
+use crate::transport::unix::{group_id, parse_mode};
+use crate::types::{CompressionType, EncryptionType, FlowControl, Framing};
+
+/// Linux limits interface names to IFNAMSIZ - 1 bytes.
+const MAX_NAME_LENGTH: usize = 15;
+/// Packets queued per channel, past this it's just a lot of memory and latency.
+const MAX_BUFFER: usize = 65536;
+/// A day, longer reconnection delays are surely a typo.
+const MAX_DELAY: f64 = 86400.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem in the config, with the section and key it was found in.
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    pub section: String,
+    pub key: &'static str,
+    pub message: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}: {}", self.section, self.key, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Validator {
+    issues: Vec<Issue>,
+}
+
+impl Validator {
+    fn push(&mut self, severity: Severity, section: &str, key: &'static str, message: String) {
+        self.issues.push(Issue {
+            severity,
+            section: section.to_string(),
+            key,
+            message,
+        });
+    }
+
+    fn error(&mut self, section: &str, key: &'static str, message: String) {
+        self.push(Severity::Error, section, key, message);
+    }
+
+    fn warning(&mut self, section: &str, key: &'static str, message: String) {
+        self.push(Severity::Warning, section, key, message);
+    }
+}
+
+/// Checks the config for mistakes that parse fine but can't work, or probably don't do what was meant.
+pub fn validate(config: &Config, peers: &[Peer]) -> Vec<Issue> {
+    let mut v = Validator::default();
+    validate_interface(&mut v, config);
+
+    let mut counters = HashMap::new();
+    let sections: Vec<String> = peers
+        .iter()
+        .map(|peer| {
+            let n = counters.entry(peer.section()).or_insert(0);
+            *n += 1;
+            format!("{} #{} \"{}\"", peer.section(), n, peer.path())
+        })
+        .collect();
+
+    for (peer, section) in peers.iter().zip(&sections) {
+        validate_peer(&mut v, config, peer, section);
+    }
+
+    for (i, (a, a_section)) in peers.iter().zip(&sections).enumerate() {
+        for (b, b_section) in peers.iter().zip(&sections).skip(i + 1) {
+            match (a, b) {
+                (Peer::Stdio(_), Peer::Stdio(_)) => v.error(
+                    b_section,
+                    "peer-stdio",
+                    format!("stdin and stdout are already used by [{}]", a_section),
+                ),
+                // clients of a shared listener are told apart by their handshake
+                _ if a.section() == b.section()
+                    && listen_path(a).is_some()
+                    && listen_path(a) == listen_path(b) =>
+                {
+                    if a.encryption() != EncryptionType::Noise
+                        || b.encryption() != EncryptionType::Noise
+                    {
+                        v.error(
+                            b_section,
+                            "path",
+                            format!(
+                                "already used by [{}], which is only possible with noise encryption",
+                                a_section
+                            ),
+                        );
+                    } else if a.public_key() == b.public_key() {
+                        v.error(
+                            b_section,
+                            "publickey",
+                            format!("already used by [{}]", a_section),
+                        );
+                    }
+                    match (a, b) {
+                        (Peer::TlsListen(x), Peer::TlsListen(y))
+                            if (&x.cert, &x.key, &x.client_ca)
+                                != (&y.cert, &y.key, &y.client_ca) =>
+                        {
+                            v.warning(
+                                b_section,
+                                "cert",
+                                format!(
+                                    "cert, key and client-ca are ignored, the listener uses those of [{}]",
+                                    a_section
+                                ),
+                            );
+                        }
+                        (Peer::WsListen(x), Peer::WsListen(y)) if x.url_path != y.url_path => {
+                            v.warning(
+                                b_section,
+                                "url-path",
+                                format!("is ignored, the listener uses the one of [{}]", a_section),
+                            );
+                        }
+                        _ => {}
+                    }
+                }
+                _ if a.section() == b.section() && a.path() == b.path() => v.error(
+                    b_section,
+                    "path",
+                    format!("already used by [{}]", a_section),
+                ),
+                _ => {}
+            }
+
+            for a_net in a.allowed_ips() {
+                for b_net in b.allowed_ips() {
+                    let (a_net, b_net) = (network(a_net), network(b_net));
+                    if a_net == b_net {
+                        v.error(
+                            b_section,
+                            "allowedips",
+                            format!("{} is already routed to [{}]", b_net, a_section),
+                        );
+                    } else if a_net.contains(b_net.network()) || b_net.contains(a_net.network()) {
+                        v.warning(
+                            b_section,
+                            "allowedips",
+                            format!(
+                                "{} overlaps {} of [{}], the most specific one wins",
+                                b_net, a_net, a_section
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    v.issues
+}
+
+fn validate_interface(v: &mut Validator, config: &Config) {
+    let interface = &config.interface;
+    let section = "interface";
+
+    if interface.name.is_empty() {
+        v.error(section, "name", "can't be empty".to_string());
+    } else if interface.name.len() > MAX_NAME_LENGTH {
+        v.error(
+            section,
+            "name",
+            format!("can be at most {} bytes long", MAX_NAME_LENGTH),
+        );
+    } else if interface
+        .name
+        .contains(|c: char| c == '/' || c.is_whitespace())
+    {
+        v.error(
+            section,
+            "name",
+            "can't contain slashes or whitespace".to_string(),
+        );
+    }
+
+    match interface.address {
+        IpNetwork::V6(_) => v.error(
+            section,
+            "address",
+            "must be an IPv4 address, IPv6 addresses go in address6".to_string(),
+        ),
+        IpNetwork::V4(address) if address.prefix() < 31 => {
+            if address.ip() == address.network() {
+                v.warning(
+                    section,
+                    "address",
+                    format!("{} is the network address of its subnet", address),
+                );
+            } else if address.ip() == address.broadcast() {
+                v.warning(
+                    section,
+                    "address",
+                    format!("{} is the broadcast address of its subnet", address),
+                );
+            }
+        }
+        IpNetwork::V4(_) => {}
+    }
+
+    match interface.buffer {
+        Some(0) => v.error(section, "buffer", "must be at least 1".to_string()),
+        Some(n) if n > MAX_BUFFER => v.warning(
+            section,
+            "buffer",
+            format!(
+                "{} packets per queue is a lot of memory and latency, try less than {}",
+                n, MAX_BUFFER
+            ),
+        ),
+        _ => {}
+    }
+}
+
+fn validate_peer(v: &mut Validator, config: &Config, peer: &Peer, section: &str) {
+    let interface = &config.interface;
+
+    if peer.allowed_ips().is_empty() {
+        v.warning(
+            section,
+            "allowedips",
+            "is empty, no packets will be routed to this peer".to_string(),
+        );
+    }
+
+    for (i, net) in peer.allowed_ips().iter().enumerate() {
+        if net.ip() != net.network() {
+            let host = match net {
+                IpNetwork::V4(_) => 32,
+                IpNetwork::V6(_) => 128,
+            };
+            v.warning(
+                section,
+                "allowedips",
+                format!(
+                    "{} has host bits set and routes all of {}, did you mean {}/{}?",
+                    net,
+                    network(net),
+                    net.ip(),
+                    host
+                ),
+            );
+        }
+
+        let inside = match net {
+            IpNetwork::V4(_) => is_inside(&interface.address, net),
+            IpNetwork::V6(_) => interface
+                .address6
+                .iter()
+                .any(|address| is_inside(&IpNetwork::V6(*address), net)),
+        };
+        if !inside {
+            v.warning(
+                section,
+                "allowedips",
+                format!(
+                    "{} is outside the subnets of {}, the kernel won't send it there without a route (see post-up)",
+                    net, interface.name
+                ),
+            );
+        }
+
+        if peer.allowed_ips()[..i]
+            .iter()
+            .any(|other| network(other) == network(net))
+        {
+            v.warning(section, "allowedips", format!("{} is listed twice", net));
+        }
+    }
+
+    match peer.encryption() {
+        EncryptionType::None => {
+            if peer.psk().is_some() {
+                v.warning(
+                    section,
+                    "psk",
+                    "is ignored without encryption, set encryption = \"chacha20poly1305\" or \"noise\""
+                        .to_string(),
+                );
+            }
+        }
+        EncryptionType::ChaCha20Poly1305 => {
+            if peer.psk().is_none() {
+                v.error(
+                    section,
+                    "psk",
+                    "is required by chacha20poly1305 encryption".to_string(),
+                );
+            }
+        }
+        EncryptionType::Noise => {
+            if peer.public_key().is_none() {
+                v.error(
+                    section,
+                    "publickey",
+                    "is required by noise encryption".to_string(),
+                );
+            }
+            if interface.privatekey.is_none() {
+                v.error(
+                    "interface",
+                    "privatekey",
+                    format!("is required by the noise encryption of [{}]", section),
+                );
+            }
+        }
+    }
+
+    if peer.encryption() != EncryptionType::Noise && peer.public_key().is_some() {
+        v.warning(
+            section,
+            "publickey",
+            "is only used by noise encryption".to_string(),
+        );
+    }
+
+    let link = peer.link();
+    for (key, delay) in [
+        ("reconnect-delay", link.reconnect_delay),
+        ("reconnect-max-delay", link.reconnect_max_delay),
+    ] {
+        if let Some(delay) = delay.filter(|d| !(d.is_finite() && *d > 0.0 && *d < MAX_DELAY)) {
+            v.error(
+                section,
+                key,
+                format!("{} must be a positive number of seconds", delay),
+            );
+        }
+    }
+    if let (Some(delay), Some(max)) = (link.reconnect_delay, link.reconnect_max_delay) {
+        if max < delay {
+            v.error(
+                section,
+                "reconnect-max-delay",
+                format!("{} is shorter than reconnect-delay ({})", max, delay),
+            );
+        }
+    }
+
+    if link.framing() != Framing::Raw
+        && matches!(peer, Peer::Udp(_) | Peer::Ws(_) | Peer::WsListen(_))
+    {
+        v.error(
+            section,
+            "framing",
+            format!(
+                "only applies to byte streams, a {} sends every frame on its own",
+                peer.section()
+            ),
+        );
+    }
+    match link.framing() {
+        framing @ (Framing::Slip | Framing::Cslip | Framing::Hdlc) => {
+            let bare = match framing {
+                Framing::Hdlc => {
+                    "isn't possible with HDLC framing, which only carries bare packets"
+                }
+                _ => "isn't possible with SLIP framing, which only carries bare packets",
+            };
+            if peer.encryption() != EncryptionType::None {
+                v.error(section, "encryption", bare.to_string());
+            }
+            if !matches!(peer.compression(), CompressionType::None) {
+                v.error(section, "compression", bare.to_string());
+            }
+            if peer.fec().is_some() {
+                v.error(section, "fec", bare.to_string());
+            }
+        }
+        Framing::Cobs if peer.fec().is_some() => v.error(
+            section,
+            "fec",
+            "isn't possible with COBS framing, which already limits the damage to one frame"
+                .to_string(),
+        ),
+        _ => {}
+    }
+    if link.framing() == Framing::Hdlc && peer.allowed_ips().iter().any(IpNetwork::is_ipv6) {
+        v.warning(
+            section,
+            "allowedips",
+            "has IPv6 networks, but only IPv4 packets go over PPP".to_string(),
+        );
+    }
+    match link.fcs {
+        Some(fcs) if fcs != 16 && fcs != 32 => v.error(
+            section,
+            "fcs",
+            format!("{} isn't a supported FCS, it can be 16 or 32 bits", fcs),
+        ),
+        Some(_) if link.framing() != Framing::Hdlc => {
+            v.warning(section, "fcs", "only applies to HDLC framing".to_string())
+        }
+        _ => {}
+    }
+
+    match peer {
+        Peer::Char(c) => {
+            match (&c.path, c.has_usb_selector()) {
+                (None, false) => v.error(
+                    section,
+                    "path",
+                    "is required, unless the device is found with usb-vid, usb-pid, usb-serial or by-id"
+                        .to_string(),
+                ),
+                (Some(_), true) => v.warning(
+                    section,
+                    "path",
+                    "is set, so usb-vid, usb-pid, usb-serial and by-id are ignored".to_string(),
+                ),
+                _ => {}
+            }
+            if c.by_id.is_some()
+                && (c.usb_vid.is_some() || c.usb_pid.is_some() || c.usb_serial.is_some())
+            {
+                v.error(
+                    section,
+                    "by-id",
+                    "can't be combined with usb-vid, usb-pid or usb-serial".to_string(),
+                );
+            }
+            for (key, id) in [("usb-vid", &c.usb_vid), ("usb-pid", &c.usb_pid)] {
+                if let Some(id) = id.as_ref().filter(|id| !is_usb_id(id)) {
+                    v.error(
+                        section,
+                        key,
+                        format!("{:?} isn't 4 hex digits, like 2e8a", id),
+                    );
+                }
+            }
+            if c.speed == Some(0) {
+                v.error(section, "speed", "can't be 0".to_string());
+            }
+            if let Some(bits) = c.data_bits.filter(|b| !(5..=8).contains(b)) {
+                v.error(
+                    section,
+                    "data-bits",
+                    format!("{} isn't between 5 and 8", bits),
+                );
+            }
+            if let Some(bits) = c.stop_bits.filter(|b| !(1..=2).contains(b)) {
+                v.error(section, "stop-bits", format!("{} isn't 1 or 2", bits));
+            }
+            if let Some(FlowControl::Software) = c.flow_control {
+                v.warning(
+                    section,
+                    "flow-control",
+                    "XON/XOFF bytes inside frames get swallowed, only use it if the link doesn't pass them through"
+                        .to_string(),
+                );
+            }
+            if let Some(timeout) = c
+                .timeout
+                .filter(|t| !(t.is_finite() && *t > 0.0 && *t < MAX_DELAY))
+            {
+                v.error(
+                    section,
+                    "timeout",
+                    format!("{} must be a positive number of seconds", timeout),
+                );
+            }
+            if let Some(fec) = c.fec.filter(|&n| n > MAX_ERRORS) {
+                v.error(
+                    section,
+                    "fec",
+                    format!(
+                        "{} is too much, at most {} bytes can be repaired per block",
+                        fec, MAX_ERRORS
+                    ),
+                );
+            }
+        }
+        Peer::Sock(s) => {
+            if !has_port(&s.path) {
+                v.error(section, "path", "must be host:port".to_string());
+            }
+        }
+        Peer::SockListen(s) => {
+            if !has_port(&s.path) {
+                v.error(section, "path", "must be host:port".to_string());
+            }
+        }
+        Peer::Tls(t) => {
+            if !has_port(&t.path) {
+                v.error(section, "path", "must be host:port".to_string());
+            }
+            check_file(v, section, "ca", &t.ca);
+            match (&t.cert, &t.key) {
+                (Some(cert), Some(key)) => {
+                    check_file(v, section, "cert", cert);
+                    check_file(v, section, "key", key);
+                }
+                (Some(_), None) => v.error(section, "key", "is required with cert".to_string()),
+                (None, Some(_)) => v.error(section, "cert", "is required with key".to_string()),
+                (None, None) => {}
+            }
+        }
+        Peer::TlsListen(t) => {
+            if !has_port(&t.path) {
+                v.error(section, "path", "must be host:port".to_string());
+            }
+            check_file(v, section, "cert", &t.cert);
+            check_file(v, section, "key", &t.key);
+            if let Some(ca) = &t.client_ca {
+                check_file(v, section, "client-ca", ca);
+            }
+        }
+        Peer::Ws(w) => {
+            match w.url.parse::<Uri>() {
+                Ok(uri) if uri.host().is_some() => match uri.scheme_str() {
+                    Some("ws") if w.ca.is_some() => {
+                        v.warning(section, "ca", "is only used with wss:// URLs".to_string())
+                    }
+                    Some("ws" | "wss") => {}
+                    _ => v.error(
+                        section,
+                        "url",
+                        "must start with ws:// or wss://".to_string(),
+                    ),
+                },
+                _ => v.error(section, "url", format!("{:?} isn't a URL", w.url)),
+            }
+            if let Some(ca) = &w.ca {
+                check_file(v, section, "ca", ca);
+            }
+            check_keepalive(v, section, w.keepalive);
+        }
+        Peer::WsListen(w) => {
+            if !has_port(&w.path) {
+                v.error(section, "path", "must be host:port".to_string());
+            }
+            if let Some(path) = w.url_path.as_ref().filter(|p| !p.starts_with('/')) {
+                v.error(section, "url-path", format!("{:?} must start with /", path));
+            }
+            check_keepalive(v, section, w.keepalive);
+        }
+        Peer::Unix(_) | Peer::Stdio(_) => {}
+        Peer::Exec(e) => {
+            if e.command.trim().is_empty() {
+                v.error(section, "command", "is empty".to_string());
+            }
+        }
+        Peer::UnixListen(u) => {
+            if let Some(mode) = u.mode.as_ref().filter(|m| parse_mode(m).is_none()) {
+                v.error(
+                    section,
+                    "mode",
+                    format!("{:?} isn't octal permissions, like 660", mode),
+                );
+            }
+            if let Some(group) = u.group.as_ref().filter(|g| group_id(g).is_none()) {
+                v.error(section, "group", format!("no such group {:?}", group));
+            }
+        }
+        Peer::Udp(u) => {
+            match (&u.remote, u.bind) {
+                (Some(remote), _) if !has_port(remote) => {
+                    v.error(section, "remote", "must be host:port".to_string());
+                }
+                (None, None) => v.error(
+                    section,
+                    "remote",
+                    "is required, unless bind is set to wait for the peer".to_string(),
+                ),
+                (None, Some(bind)) if bind.port() == 0 => v.error(
+                    section,
+                    "bind",
+                    "needs a port, the peer has to know where to send its frames without a remote"
+                        .to_string(),
+                ),
+                _ => {}
+            }
+            check_keepalive(v, section, u.keepalive);
+            let roaming = u.roaming == Some(true) || u.remote.is_none();
+            if roaming && peer.encryption() == EncryptionType::None {
+                v.warning(
+                    section,
+                    "roaming",
+                    "without encryption, anyone can redirect the traffic of this peer".to_string(),
+                );
+            }
+        }
+    }
+}
+
+fn check_file(v: &mut Validator, section: &str, key: &'static str, path: &str) {
+    if let Err(e) = std::fs::metadata(path) {
+        v.error(section, key, format!("{}: {}", path, e));
+    }
+}
+
+fn check_keepalive(v: &mut Validator, section: &str, keepalive: Option<f64>) {
+    if let Some(keepalive) = keepalive.filter(|k| !(k.is_finite() && *k >= 0.0 && *k < MAX_DELAY)) {
+        v.error(
+            section,
+            "keepalive",
+            format!("{} must be a number of seconds", keepalive),
+        );
+    }
+}
+
+fn has_port(address: &str) -> bool {
+    address
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+}
+
+fn is_usb_id(id: &str) -> bool {
+    id.len() == 4 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The network itself, without host bits.
+fn network(net: &IpNetwork) -> IpNetwork {
+    IpNetwork::new(net.network(), net.prefix()).unwrap_or(*net)
+}
+
+fn is_inside(subnet: &IpNetwork, net: &IpNetwork) -> bool {
+    subnet.prefix() <= net.prefix() && subnet.contains(net.network())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    const INTERFACE: &str = r#"
+        [interface]
+        address = "10.1.0.1/24"
+        address6 = ["fd00::1/64"]
+        name = "ip2char0"
+    "#;
+
+    /// What `validate` finds in a config, as `(section, key, severity)`.
+    fn check(config: &str) -> Vec<(String, &'static str, Severity)> {
+        let config: Config = toml::from_str(config).unwrap();
+        let peers = config.get_all_peers();
+        validate(&config, &peers)
+            .into_iter()
+            .map(|issue| (issue.section, issue.key, issue.severity))
+            .collect()
+    }
+
+    /// Same, for peers behind the usual interface.
+    fn check_peers(peers: &str) -> Vec<(String, &'static str, Severity)> {
+        check(&format!("{}\n{}", INTERFACE, peers))
+    }
+
+    fn issue(
+        section: &str,
+        key: &'static str,
+        severity: Severity,
+    ) -> (String, &'static str, Severity) {
+        (section.to_string(), key, severity)
+    }
+
+    #[test]
+    fn clean_config() {
+        let peers = format!(
+            r#"
+            [[peer-char]]
+            path = "/dev/ttyACM0"
+            allowedips = ["10.1.0.2/32", "fd00::2/128"]
+            speed = 115200
+            fec = 4
+            encryption = "chacha20poly1305"
+            psk = "{KEY}"
+
+            [[peer-sock]]
+            path = "10.0.0.1:9000"
+            allowedips = ["10.1.0.3/32"]
+
+            [[peer-sock-listen]]
+            path = "0.0.0.0:9000"
+            allowedips = ["10.1.0.4/32"]
+            "#
+        );
+        assert_eq!(check_peers(&peers), []);
+    }
+
+    #[test]
+    fn shipped_config() {
+        let issues = check(include_str!("../ip2char.toml"));
+        assert!(
+            issues
+                .iter()
+                .all(|(_, _, severity)| *severity == Severity::Warning),
+            "{:?}",
+            issues
+        );
+    }
+
+    #[test]
+    fn interface() {
+        let config = r#"
+            [interface]
+            address = "fd00::1/64"
+            name = "a name that is way too long"
+            buffer = 0
+        "#;
+        assert_eq!(
+            check(config),
+            [
+                issue("interface", "name", Severity::Error),
+                issue("interface", "address", Severity::Error),
+                issue("interface", "buffer", Severity::Error),
+            ]
+        );
+
+        let config = r#"
+            [interface]
+            address = "10.1.0.0/24"
+            name = "ip2char0"
+            buffer = 100000
+        "#;
+        assert_eq!(
+            check(config),
+            [
+                issue("interface", "address", Severity::Warning),
+                issue("interface", "buffer", Severity::Warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_allowedips() {
+        let peers = r#"
+            [[peer-char]]
+            path = "/dev/ttyACM0"
+            allowedips = ["10.1.0.0/28", "fd00::/120"]
+
+            [[peer-char]]
+            path = "/dev/ttyACM1"
+            allowedips = ["10.1.0.0/28"]
+
+            [[peer-sock]]
+            path = "10.0.0.1:9000"
+            allowedips = ["10.1.0.4/32", "fd00::10/124"]
+        "#;
+        assert_eq!(
+            check_peers(peers),
+            [
+                issue(
+                    "peer-char #2 \"/dev/ttyACM1\"",
+                    "allowedips",
+                    Severity::Error
+                ),
+                issue(
+                    "peer-sock #1 \"10.0.0.1:9000\"",
+                    "allowedips",
+                    Severity::Warning
+                ),
+                issue(
+                    "peer-sock #1 \"10.0.0.1:9000\"",
+                    "allowedips",
+                    Severity::Warning
+                ),
+                issue(
+                    "peer-sock #1 \"10.0.0.1:9000\"",
+                    "allowedips",
+                    Severity::Warning
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn allowedips() {
+        let peers = r#"
+            [[peer-char]]
+            path = "/dev/ttyACM0"
+            allowedips = []
+
+            [[peer-char]]
+            path = "/dev/ttyACM1"
+            allowedips = ["10.1.0.5/24", "192.168.0.1/32", "fd01::1/128", "10.1.0.9/32", "10.1.0.9/32"]
+        "#;
+        let second = "peer-char #2 \"/dev/ttyACM1\"";
+        assert_eq!(
+            check_peers(peers),
+            [
+                issue(
+                    "peer-char #1 \"/dev/ttyACM0\"",
+                    "allowedips",
+                    Severity::Warning
+                ),
+                // host bits
+                issue(second, "allowedips", Severity::Warning),
+                // outside of the interface subnets
+                issue(second, "allowedips", Severity::Warning),
+                issue(second, "allowedips", Severity::Warning),
+                // listed twice
+                issue(second, "allowedips", Severity::Warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_paths() {
+        let peers = r#"
+            [[peer-sock]]
+            path = "10.0.0.1:9000"
+            allowedips = ["10.1.0.2/32"]
+
+            [[peer-sock]]
+            path = "10.0.0.1:9000"
+            allowedips = ["10.1.0.3/32"]
+
+            [[peer-sock-listen]]
+            path = "10.0.0.1:9000"
+            allowedips = ["10.1.0.4/32"]
+        "#;
+        assert_eq!(
+            check_peers(peers),
+            [issue(
+                "peer-sock #2 \"10.0.0.1:9000\"",
+                "path",
+                Severity::Error
+            )]
+        );
+    }
+
+    #[test]
+    fn encryption() {
+        let peers = format!(
+            r#"
+            [[peer-char]]
+            path = "/dev/ttyACM0"
+            allowedips = ["10.1.0.2/32"]
+            psk = "{KEY}"
+            publickey = "{KEY}"
+
+            [[peer-char]]
+            path = "/dev/ttyACM1"
+            allowedips = ["10.1.0.3/32"]
+            encryption = "chacha20poly1305"
+
+            [[peer-char]]
+            path = "/dev/ttyACM2"
+            allowedips = ["10.1.0.4/32"]
+            encryption = "noise"
+            "#
+        );
+        assert_eq!(
+            check_peers(&peers),
+            [
+                issue("peer-char #1 \"/dev/ttyACM0\"", "psk", Severity::Warning),
+                issue(
+                    "peer-char #1 \"/dev/ttyACM0\"",
+                    "publickey",
+                    Severity::Warning
+                ),
+                issue("peer-char #2 \"/dev/ttyACM1\"", "psk", Severity::Error),
+                issue(
+                    "peer-char #3 \"/dev/ttyACM2\"",
+                    "publickey",
+                    Severity::Error
+                ),
+                issue("interface", "privatekey", Severity::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn transports() {
+        let peers = format!(
+            r#"
+            [[peer-char]]
+            path = "/dev/ttyACM0"
+            allowedips = ["10.1.0.2/32"]
+            speed = 0
+            fec = {}
+
+            [[peer-sock]]
+            path = "10.0.0.1"
+            allowedips = ["10.1.0.3/32"]
+
+            [[peer-sock-listen]]
+            path = "0.0.0.0:http"
+            allowedips = ["10.1.0.4/32"]
+            "#,
+            MAX_ERRORS + 1
+        );
+        assert_eq!(
+            check_peers(&peers),
+            [
+                issue("peer-char #1 \"/dev/ttyACM0\"", "speed", Severity::Error),
+                issue("peer-char #1 \"/dev/ttyACM0\"", "fec", Severity::Error),
+                issue("peer-sock #1 \"10.0.0.1\"", "path", Severity::Error),
+                issue(
+                    "peer-sock-listen #1 \"0.0.0.0:http\"",
+                    "path",
+                    Severity::Error
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn usb_selectors() {
+        let peers = r#"
+            [[peer-char]]
+            allowedips = ["10.1.0.2/32"]
+
+            [[peer-char]]
+            path = "/dev/ttyACM0"
+            usb-vid = "2e8a"
+            allowedips = ["10.1.0.3/32"]
+
+            [[peer-char]]
+            by-id = "*Pico*"
+            usb-serial = "E661"
+            allowedips = ["10.1.0.4/32"]
+
+            [[peer-char]]
+            usb-vid = "2e8a0"
+            usb-pid = "xyzw"
+            allowedips = ["10.1.0.5/32"]
+
+            [[peer-char]]
+            usb-vid = "2E8A"
+            usb-pid = "000a"
+            usb-serial = "E661"
+            allowedips = ["10.1.0.6/32"]
+        "#;
+        assert_eq!(
+            check_peers(peers),
+            [
+                issue("peer-char #1 \"usb:*:*\"", "path", Severity::Error),
+                issue("peer-char #2 \"/dev/ttyACM0\"", "path", Severity::Warning),
+                issue("peer-char #3 \"by-id:*Pico*\"", "by-id", Severity::Error),
+                issue(
+                    "peer-char #4 \"usb:2e8a0:xyzw\"",
+                    "usb-vid",
+                    Severity::Error
+                ),
+                issue(
+                    "peer-char #4 \"usb:2e8a0:xyzw\"",
+                    "usb-pid",
+                    Severity::Error
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn udp() {
+        let peers = format!(
+            r#"
+            [[peer-udp]]
+            remote = "vps.example.com"
+            keepalive = -1
+            allowedips = ["10.1.0.2/32"]
+
+            [[peer-udp]]
+            allowedips = ["10.1.0.3/32"]
+
+            [[peer-udp]]
+            bind = "0.0.0.0:0"
+            allowedips = ["10.1.0.4/32"]
+
+            [[peer-udp]]
+            remote = "vps.example.com:5000"
+            roaming = true
+            allowedips = ["10.1.0.5/32"]
+
+            [[peer-udp]]
+            bind = "0.0.0.0:5000"
+            allowedips = ["10.1.0.6/32"]
+            encryption = "chacha20poly1305"
+            psk = "{key}"
+
+            [[peer-udp]]
+            remote = "vps.example.com:5001"
+            keepalive = 0
+            allowedips = ["10.1.0.7/32"]
+            "#,
+            key = KEY
+        );
+        assert_eq!(
+            check_peers(&peers),
+            [
+                issue(
+                    "peer-udp #1 \"udp:vps.example.com\"",
+                    "remote",
+                    Severity::Error
+                ),
+                issue(
+                    "peer-udp #1 \"udp:vps.example.com\"",
+                    "keepalive",
+                    Severity::Error
+                ),
+                issue("peer-udp #2 \"udp\"", "remote", Severity::Error),
+                issue("peer-udp #2 \"udp\"", "roaming", Severity::Warning),
+                issue("peer-udp #3 \"udp:0.0.0.0:0\"", "bind", Severity::Error),
+                issue(
+                    "peer-udp #3 \"udp:0.0.0.0:0\"",
+                    "roaming",
+                    Severity::Warning
+                ),
+                issue(
+                    "peer-udp #4 \"udp:vps.example.com:5000\"",
+                    "roaming",
+                    Severity::Warning
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unix_sockets() {
+        let peers = r#"
+            [[peer-unix-listen]]
+            path = "/run/ip2char/a.sock"
+            mode = "999"
+            group = "no-such-group-here"
+            allowedips = ["10.1.0.2/32"]
+
+            [[peer-unix-listen]]
+            path = "/run/ip2char/b.sock"
+            mode = "0660"
+            group = "0"
+            allowedips = ["10.1.0.3/32"]
+        "#;
+        assert_eq!(
+            check_peers(peers),
+            [
+                issue(
+                    "peer-unix-listen #1 \"/run/ip2char/a.sock\"",
+                    "mode",
+                    Severity::Error
+                ),
+                issue(
+                    "peer-unix-listen #1 \"/run/ip2char/a.sock\"",
+                    "group",
+                    Severity::Error
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnection() {
+        let peers = r#"
+            [[peer-sock]]
+            path = "10.0.0.1:9000"
+            allowedips = ["10.1.0.2/32"]
+            reconnect-delay = 0
+            reconnect-max-delay = inf
+
+            [[peer-sock]]
+            path = "10.0.0.2:9000"
+            allowedips = ["10.1.0.3/32"]
+            reconnect-delay = 10
+            reconnect-max-delay = 5
+        "#;
+        assert_eq!(
+            check_peers(peers),
+            [
+                issue(
+                    "peer-sock #1 \"10.0.0.1:9000\"",
+                    "reconnect-delay",
+                    Severity::Error
+                ),
+                issue(
+                    "peer-sock #1 \"10.0.0.1:9000\"",
+                    "reconnect-max-delay",
+                    Severity::Error
+                ),
+                issue(
+                    "peer-sock #2 \"10.0.0.2:9000\"",
+                    "reconnect-max-delay",
+                    Severity::Error
+                ),
+            ]
+        );
+    }
+}